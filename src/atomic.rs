@@ -0,0 +1,152 @@
+use std::{
+    ffi::OsString,
+    io,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use tokio::fs::File;
+
+/// A file received into a hidden sibling and moved over its destination once complete
+///
+/// The sibling outlives failed transfers so that they can be resumed.
+#[derive(Debug, Clone)]
+pub struct PartFile {
+    path: PathBuf,
+    part_path: PathBuf,
+}
+impl PartFile {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            part_path: sibling(path, ".", ".part"),
+        }
+    }
+
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    /// Open the part file for reading and writing, creating it if missing
+    ///
+    /// The part file stays locked until the returned handle is dropped or committed
+    /// so that a second transfer into the same destination fails instead of interleaving with this one.
+    ///
+    /// A symlink in place of the part file is not followed.
+    pub async fn open(&self, truncate: bool) -> io::Result<File> {
+        loop {
+            let file = self.open_unlocked().await?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!(
+                            "another transfer is writing to {}",
+                            self.part_path.display()
+                        ),
+                    ));
+                }
+                return Err(e);
+            }
+
+            // The previous holder might have committed the file we locked in the meantime
+            let locked = file.metadata().await?;
+            match tokio::fs::symlink_metadata(&self.part_path).await {
+                Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => {
+                    if truncate {
+                        file.set_len(0).await?;
+                    }
+                    return Ok(file);
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Open another handle to the part file locked by [`Self::open`]
+    pub async fn reopen(&self) -> io::Result<File> {
+        self.open_unlocked().await
+    }
+
+    async fn open_unlocked(&self) -> io::Result<File> {
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&self.part_path)
+            .await
+    }
+
+    /// Persist `file` and replace the destination with it
+    ///
+    /// With `backup`, the replaced destination is kept with a `~` suffix.
+    pub async fn commit(&self, file: File, backup: bool) -> io::Result<()> {
+        file.sync_all().await?;
+
+        if backup && tokio::fs::symlink_metadata(&self.path).await.is_ok() {
+            let backup_path = sibling(&self.path, "", "~");
+            let _ = tokio::fs::remove_file(&backup_path).await;
+            if tokio::fs::hard_link(&self.path, &backup_path)
+                .await
+                .is_err()
+            {
+                tokio::fs::copy(&self.path, &backup_path).await?;
+            }
+        }
+        tokio::fs::rename(&self.part_path, &self.path).await?;
+        // Only released once the part file is gone so that no other transfer writes to the destination
+        drop(file);
+
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+        Ok(())
+    }
+}
+
+fn sibling(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut file_name = OsString::from(prefix);
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn second_writer_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = PartFile::new(&dir.path().join("out"));
+        let mut first = part.open(true).await.unwrap();
+        first.write_all(b"first").await.unwrap();
+
+        let e = part.open(true).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        let e = part.open(false).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+        part.commit(first, false).await.unwrap();
+        let second = part.open(true).await.unwrap();
+        assert_eq!(second.metadata().await.unwrap().len(), 0);
+        assert_eq!(std::fs::read(dir.path().join("out")).unwrap(), b"first");
+    }
+
+    #[tokio::test]
+    async fn lock_is_released_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = PartFile::new(&dir.path().join("out"));
+        drop(part.open(true).await.unwrap());
+        part.open(false).await.unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use std::{io, path::Path};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{handshake::Role, FileTransferError};
+
+const KEY_CONTEXT: &str = "file_transfer pre-shared key authentication v1";
+const NONCE_LEN: usize = 32;
+
+/// Read a pre-shared key from `path`, ignoring trailing whitespace
+pub async fn read_psk(path: &Path) -> io::Result<Vec<u8>> {
+    let mut psk = tokio::fs::read(path).await?;
+    while psk.last().is_some_and(|byte| byte.is_ascii_whitespace()) {
+        psk.pop();
+    }
+    if psk.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("empty pre-shared key: {}", path.display()),
+        ));
+    }
+    Ok(psk)
+}
+
+/// Prove to the peer that this side knows the pre-shared key and make the peer prove the same
+///
+/// Each side sends a fresh nonce and then a MAC over both nonces keyed with the pre-shared key.
+/// The MAC also covers the role of its sender so that it cannot be reflected back.
+///
+/// Without `psk`, the exchange is still carried out with an invalid MAC so that the peer can report the failure.
+pub async fn authenticate<R, W>(
+    read: &mut R,
+    write: &mut W,
+    role: Role,
+    psk: Option<&[u8]>,
+) -> Result<(), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut local_nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut local_nonce).map_err(io::Error::from)?;
+    write.write_all(&local_nonce).await?;
+    write.flush().await?;
+    let mut peer_nonce = [0; NONCE_LEN];
+    read.read_exact(&mut peer_nonce).await?;
+
+    let Some(psk) = psk else {
+        write.write_all(&[0; blake3::OUT_LEN]).await?;
+        write.flush().await?;
+        read.read_exact(&mut [0; blake3::OUT_LEN]).await?;
+        return Err(FileTransferError::unauthenticated(
+            "peer requires a pre-shared key but none is configured",
+        ));
+    };
+    let key = blake3::derive_key(KEY_CONTEXT, psk);
+    let local_mac = mac(&key, role, &peer_nonce, &local_nonce);
+    write.write_all(local_mac.as_bytes()).await?;
+    write.flush().await?;
+
+    let mut peer_mac = [0; blake3::OUT_LEN];
+    read.read_exact(&mut peer_mac).await?;
+    let peer_role = match role {
+        Role::Push => Role::Pull,
+        Role::Pull => Role::Push,
+    };
+    // `blake3::Hash` compares in constant time
+    if blake3::Hash::from_bytes(peer_mac) != mac(&key, peer_role, &local_nonce, &peer_nonce) {
+        return Err(FileTransferError::unauthenticated(
+            "peer does not know the pre-shared key",
+        ));
+    }
+    Ok(())
+}
+
+/// MAC sent by `role` after receiving `challenge` and sending `nonce`
+fn mac(key: &[u8; 32], role: Role, challenge: &[u8], nonce: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(key);
+    hasher.update(&[role.to_u8()]);
+    hasher.update(challenge);
+    hasher.update(nonce);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Authenticate a pusher holding `push_psk` against a puller holding `pull_psk`
+    async fn exchange(
+        push_psk: Option<&[u8]>,
+        pull_psk: Option<&[u8]>,
+    ) -> (Result<(), FileTransferError>, Result<(), FileTransferError>) {
+        let (push_stream, pull_stream) = tokio::io::duplex(1024);
+        let (mut push_read, mut push_write) = tokio::io::split(push_stream);
+        let (mut pull_read, mut pull_write) = tokio::io::split(pull_stream);
+        tokio::join!(
+            authenticate(&mut push_read, &mut push_write, Role::Push, push_psk),
+            authenticate(&mut pull_read, &mut pull_write, Role::Pull, pull_psk),
+        )
+    }
+
+    fn assert_unauthenticated(result: Result<(), FileTransferError>) {
+        match result {
+            Err(FileTransferError::Unauthenticated(_)) => {}
+            other => panic!("expected an authentication error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_keys() {
+        let (pushed, pulled) = exchange(Some(b"secret"), Some(b"secret")).await;
+        pushed.unwrap();
+        pulled.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_key_on_either_side() {
+        for (push_psk, pull_psk) in [(b"secret", b"guess!"), (b"guess!", b"secret")] {
+            let (pushed, pulled) = exchange(Some(push_psk), Some(pull_psk)).await;
+            assert_unauthenticated(pushed);
+            assert_unauthenticated(pulled);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_on_one_side_only() {
+        for (push_psk, pull_psk) in [(Some(&b"secret"[..]), None), (None, Some(&b"secret"[..]))] {
+            let (pushed, pulled) = exchange(push_psk, pull_psk).await;
+            assert_unauthenticated(pushed);
+            assert_unauthenticated(pulled);
+        }
+    }
+
+    #[tokio::test]
+    async fn reflected_challenge_is_rejected() {
+        let (stream, attacker) = tokio::io::duplex(1024);
+        let (mut read, mut write) = tokio::io::split(stream);
+        let (mut attacker_read, mut attacker_write) = tokio::io::split(attacker);
+        // Without the key, the attacker echoes the nonce and then the MAC it is sent
+        let reflecting = async {
+            let mut nonce = [0; NONCE_LEN];
+            attacker_read.read_exact(&mut nonce).await.unwrap();
+            attacker_write.write_all(&nonce).await.unwrap();
+            let mut mac = [0; blake3::OUT_LEN];
+            attacker_read.read_exact(&mut mac).await.unwrap();
+            attacker_write.write_all(&mac).await.unwrap();
+        };
+        let (authenticated, ()) = tokio::join!(
+            authenticate(&mut read, &mut write, Role::Pull, Some(b"secret")),
+            reflecting,
+        );
+        assert_unauthenticated(authenticated);
+    }
+}
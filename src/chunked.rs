@@ -0,0 +1,122 @@
+use std::io;
+
+use async_async_io::read::{AsyncAsyncRead, PollRead};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const CHUNK_LEN: usize = 1024 * 64;
+pub const MAX_CHUNK_LEN: u32 = 1024 * 1024;
+
+/// Copy `read` to `write` as a sequence of length-prefixed chunks closed by an empty chunk
+///
+/// Return the number of bytes written, framing included.
+pub async fn write_chunks<R, W>(mut read: R, write: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut written = 0;
+    loop {
+        let mut len = 0;
+        while len < buf.len() {
+            let n = read.read(&mut buf[len..]).await?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        write_chunk(write, &buf[..len]).await?;
+        written += 4 + len as u64;
+        if len == 0 {
+            return Ok(written);
+        }
+    }
+}
+
+/// Write `chunk` with its length prefix; an empty chunk marks the end
+///
+/// Chunks longer than [`MAX_CHUNK_LEN`] are refused since [`ChunkedRead`] would reject them.
+pub async fn write_chunk<W>(write: &mut W, chunk: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u32::try_from(chunk.len())
+        .ok()
+        .filter(|len| *len <= MAX_CHUNK_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunk too long: {} bytes", chunk.len()),
+            )
+        })?;
+    write.write_u32(len).await?;
+    write.write_all(chunk).await
+}
+
+/// Read the payload of chunks written by [`write_chunks`] up to the empty chunk
+pub struct ChunkedRead<R> {
+    read: R,
+    remaining: usize,
+    end: bool,
+}
+impl<R> ChunkedRead<R> {
+    pub fn new(read: R) -> Self {
+        Self {
+            read,
+            remaining: 0,
+            end: false,
+        }
+    }
+
+    pub fn into_async_read(self) -> PollRead<Self> {
+        PollRead::new(self)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.read
+    }
+}
+impl<R> AsyncAsyncRead for ChunkedRead<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            if self.end {
+                return Ok(0);
+            }
+            let len = self.read.read_u32().await?;
+            if MAX_CHUNK_LEN < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk too long: {len} bytes"),
+                ));
+            }
+            if len == 0 {
+                self.end = true;
+                return Ok(0);
+            }
+            self.remaining = len as usize;
+        }
+        let bytes = self.remaining.min(buf.len());
+        if bytes == 0 {
+            return Ok(0);
+        }
+        self.read.read_exact(&mut buf[..bytes]).await?;
+        self.remaining -= bytes;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn overlong_chunk_is_refused() {
+        let chunk = vec![0; MAX_CHUNK_LEN as usize + 1];
+        let e = write_chunk(&mut vec![], &chunk).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        write_chunk(&mut vec![], &chunk[1..]).await.unwrap();
+    }
+}
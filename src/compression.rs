@@ -0,0 +1,66 @@
+use std::io;
+
+use async_compression::tokio::bufread::{
+    GzipDecoder, GzipEncoder, Lz4Decoder, Lz4Encoder, ZstdDecoder, ZstdEncoder,
+};
+use clap::ValueEnum;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+use crate::{chunked::write_chunks, FileTransferError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+    Gzip,
+}
+impl Compression {
+    pub(crate) fn codec(compression: Option<Self>) -> u8 {
+        match compression {
+            None => 0,
+            Some(Compression::Zstd) => 1,
+            Some(Compression::Lz4) => 2,
+            Some(Compression::Gzip) => 3,
+        }
+    }
+
+    pub(crate) fn from_codec(codec: u8) -> Result<Option<Self>, FileTransferError> {
+        Ok(Some(match codec {
+            0 => return Ok(None),
+            1 => Compression::Zstd,
+            2 => Compression::Lz4,
+            3 => Compression::Gzip,
+            _ => {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "unknown codec: {codec}"
+                )))
+            }
+        }))
+    }
+
+    /// Compress `read` into chunks written to `write`
+    ///
+    /// Return the number of bytes written.
+    pub(crate) async fn encode<R, W>(self, read: R, write: &mut W) -> io::Result<u64>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Compression::Zstd => write_chunks(ZstdEncoder::new(read), write).await,
+            Compression::Lz4 => write_chunks(Lz4Encoder::new(read), write).await,
+            Compression::Gzip => write_chunks(GzipEncoder::new(read), write).await,
+        }
+    }
+
+    pub(crate) fn decoder<'a, R>(self, read: R) -> Box<dyn AsyncRead + Unpin + Send + 'a>
+    where
+        R: AsyncBufRead + Unpin + Send + 'a,
+    {
+        match self {
+            Compression::Zstd => Box::new(ZstdDecoder::new(read)),
+            Compression::Lz4 => Box::new(Lz4Decoder::new(read)),
+            Compression::Gzip => Box::new(GzipDecoder::new(read)),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Args;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    request::{read_remote_path, write_accepted, write_rejected, PathRequest},
+    tcp::perform_split,
+    timeout::IdleTimeout,
+    FileTransferCommand, FileTransferError, FileTransferStats, PullFileArgs, PushFileArgs,
+    Rejection, Sandbox, ServerTlsArgs,
+};
+
+/// Limits of a server accepting many peers
+#[derive(Debug, Clone, Args)]
+pub struct DaemonArgs {
+    /// Most transfers to run at once; further peers wait to be accepted
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: u32,
+    /// Drop a peer once reading from or writing to it has stalled for this many seconds
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: u64,
+}
+
+/// What became of a peer of a [`daemon`]
+#[derive(Debug)]
+pub enum DaemonEvent {
+    /// Accepting a connection failed; the daemon keeps accepting others
+    AcceptFailed(io::Error),
+    /// The transfer with `peer` has ended
+    Served {
+        peer: SocketAddr,
+        result: Result<FileTransferStats, FileTransferError>,
+    },
+}
+
+/// Run `command` with every peer connecting to `listener` until `shutdown` completes
+///
+/// Each peer names a path for `command` to transfer under its `output_file` or `source_file`.
+/// Peers naming a path that is missing or outside of that root are sent a rejection.
+/// `report` is called with how every peer fared.
+///
+/// On shutdown, no more peers are accepted and the transfers in flight are allowed to finish.
+pub async fn daemon<F>(
+    listener: TcpListener,
+    args: &DaemonArgs,
+    tls: &ServerTlsArgs,
+    command: &FileTransferCommand,
+    shutdown: impl Future<Output = ()>,
+    report: F,
+) -> Result<(), FileTransferError>
+where
+    F: Fn(DaemonEvent) + Send + Sync + 'static,
+{
+    let acceptor = tls.acceptor()?;
+    let limit = Arc::new(Semaphore::new(args.max_connections as usize));
+    let timeout = Duration::from_secs(args.timeout);
+    let report = Arc::new(report);
+    tokio::pin!(shutdown);
+
+    loop {
+        let permit = tokio::select! {
+            permit = limit.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+            () = &mut shutdown => break,
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    report(DaemonEvent::AcceptFailed(e));
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+        let acceptor = acceptor.clone();
+        let command = command.clone();
+        let report = report.clone();
+        tokio::spawn(async move {
+            let result = serve_connection(stream, acceptor, timeout, command).await;
+            report(DaemonEvent::Served { peer, result });
+            drop(permit);
+        });
+    }
+
+    drop(listener);
+    let _ = limit.acquire_many(args.max_connections).await;
+    Ok(())
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    timeout: Duration,
+    command: FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    stream.set_nodelay(true)?;
+    let stream = IdleTimeout::new(stream, timeout);
+    match acceptor {
+        Some(acceptor) => serve_stream(acceptor.accept(stream).await?, command).await,
+        None => serve_stream(stream, command).await,
+    }
+}
+
+async fn serve_stream<S>(
+    stream: S,
+    command: FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(stream);
+    perform_split(read, write, &command, Some(PathRequest::Receive)).await
+}
+
+/// Read the path named by the connecting peer and return `command` pointed at it
+///
+/// `command` is already negotiated so that the path is checked against the features in use.
+///
+/// Only called once the peer is authenticated so that it learns nothing of the file system before.
+pub(crate) async fn receive_request<R, W>(
+    read: &mut R,
+    write: &mut W,
+    command: FileTransferCommand,
+) -> Result<FileTransferCommand, FileTransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let path = read_remote_path(read).await?;
+    let command = match resolve(command, &path).await {
+        Ok(command) => command,
+        Err(FileTransferError::Rejected { reason, message }) => {
+            write_rejected(write, reason, &message).await?;
+            return Err(FileTransferError::Rejected { reason, message });
+        }
+        Err(e) => return Err(e),
+    };
+    write_accepted(write).await?;
+    Ok(command)
+}
+
+/// Point `command` at `path` under its root
+async fn resolve(
+    command: FileTransferCommand,
+    path: &Path,
+) -> Result<FileTransferCommand, FileTransferError> {
+    match command {
+        FileTransferCommand::Pull(args) => {
+            let output_file = confine(&args.output_file, path).await?;
+            if let Some(parent) = output_file.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            Ok(FileTransferCommand::Pull(PullFileArgs {
+                output_file,
+                ..args
+            }))
+        }
+        FileTransferCommand::Push(args) => {
+            let source_file = confine(&args.source_file, path).await?;
+            let metadata = tokio::fs::metadata(&source_file).await.map_err(|e| {
+                rejected(io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+            })?;
+            match (args.recursive, metadata.is_dir()) {
+                (true, false) => Err(FileTransferError::Rejected {
+                    reason: Rejection::NotFound,
+                    message: format!("{}: not a directory", path.display()),
+                }),
+                (false, true) => Err(FileTransferError::Rejected {
+                    reason: Rejection::NotFound,
+                    message: format!("{}: not a file", path.display()),
+                }),
+                _ => Ok(FileTransferCommand::Push(PushFileArgs {
+                    source_file,
+                    ..args
+                })),
+            }
+        }
+    }
+}
+
+async fn confine(root: &Path, path: &Path) -> Result<PathBuf, FileTransferError> {
+    Sandbox::new(root).resolve(path).await.map_err(rejected)
+}
+
+/// Turn the errors the peer is entitled to know about into rejections
+fn rejected(e: io::Error) -> FileTransferError {
+    let reason = match e.kind() {
+        io::ErrorKind::NotFound => Rejection::NotFound,
+        io::ErrorKind::PermissionDenied => Rejection::Forbidden,
+        _ => return e.into(),
+    };
+    FileTransferError::Rejected {
+        reason,
+        message: e.to_string(),
+    }
+}
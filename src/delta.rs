@@ -0,0 +1,615 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    mem,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{error::to_usize, progress::ProgressTracker, FileTransferError};
+
+const MIN_BLOCK_LEN: u32 = 2 * 1024;
+const MAX_BLOCK_LEN: u32 = 128 * 1024;
+const MAX_BLOCKS: u64 = 1 << 23;
+/// Blocks to make room for before any arrive, since the announced count is not trusted
+const INITIAL_BLOCKS: u64 = 1 << 16;
+const MAX_LITERAL_LEN: u32 = 1024 * 1024;
+const READ_LEN: usize = 1024 * 1024;
+const STRONG_LEN: usize = 16;
+
+const END: u8 = 0;
+const LITERAL: u8 = 1;
+const COPY: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockSignature {
+    weak: u32,
+    strong: [u8; STRONG_LEN],
+}
+impl BlockSignature {
+    fn of(block: &[u8]) -> Self {
+        Self {
+            weak: Rolling::new(block).digest(),
+            strong: strong(block),
+        }
+    }
+}
+
+/// Checksums of the blocks of the file the puller already has
+#[derive(Debug, Clone)]
+pub struct Signature {
+    block_len: u32,
+    basis_len: u64,
+    blocks: Vec<BlockSignature>,
+}
+impl Signature {
+    /// The signature of a missing basis file
+    pub fn empty() -> Self {
+        Self {
+            block_len: MIN_BLOCK_LEN,
+            basis_len: 0,
+            blocks: vec![],
+        }
+    }
+
+    /// Checksum every block of `basis`, with blocks about as long as there are blocks
+    pub async fn compute(basis: &File) -> io::Result<Self> {
+        let mut basis = basis.try_clone().await?.into_std().await;
+        tokio::task::spawn_blocking(move || {
+            let len = basis.metadata()?.len();
+            let block_len = (len as f64).sqrt() as u32;
+            let block_len = block_len.clamp(MIN_BLOCK_LEN, MAX_BLOCK_LEN);
+            basis.seek(SeekFrom::Start(0))?;
+            let mut basis = io::BufReader::with_capacity(READ_LEN, basis);
+
+            let mut blocks = vec![];
+            let mut basis_len = 0;
+            let mut block = vec![0; block_len as usize];
+            loop {
+                let n = read_full(&mut basis, &mut block)?;
+                if n == 0 {
+                    break;
+                }
+                blocks.push(BlockSignature::of(&block[..n]));
+                basis_len += n as u64;
+                if n < block.len() {
+                    break;
+                }
+            }
+            Ok(Self {
+                block_len,
+                basis_len,
+                blocks,
+            })
+        })
+        .await?
+    }
+
+    /// Offset and length of the `index`th block in the basis file
+    fn block(&self, index: u32) -> (u64, usize) {
+        let offset = u64::from(index) * u64::from(self.block_len);
+        let len = (self.basis_len - offset).min(u64::from(self.block_len));
+        (offset, len as usize)
+    }
+
+    pub async fn write_to<W>(&self, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u32(self.block_len).await?;
+        write.write_u64(self.basis_len).await?;
+        for block in &self.blocks {
+            write.write_u32(block.weak).await?;
+            write.write_all(&block.strong).await?;
+        }
+        write.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read_from<R>(read: &mut R) -> Result<Self, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let block_len = read.read_u32().await?;
+        if !(MIN_BLOCK_LEN..=MAX_BLOCK_LEN).contains(&block_len) {
+            return Err(FileTransferError::protocol_violation(format!(
+                "invalid block length: {block_len}"
+            )));
+        }
+        let basis_len = read.read_u64().await?;
+        let count = basis_len.div_ceil(u64::from(block_len));
+        if MAX_BLOCKS < count {
+            return Err(FileTransferError::protocol_violation(format!(
+                "too many blocks: {count}"
+            )));
+        }
+        let mut blocks = Vec::with_capacity(to_usize(count.min(INITIAL_BLOCKS))?);
+        for _ in 0..count {
+            let weak = read.read_u32().await?;
+            let mut strong = [0; STRONG_LEN];
+            read.read_exact(&mut strong).await?;
+            blocks.push(BlockSignature { weak, strong });
+        }
+        Ok(Self {
+            block_len,
+            basis_len,
+            blocks,
+        })
+    }
+}
+
+/// Instruction for rebuilding the source file on the puller
+#[derive(Debug)]
+enum DeltaOp {
+    Literal(Vec<u8>),
+    /// Reuse `count` consecutive blocks of the basis file starting from block `index`
+    Copy {
+        index: u32,
+        count: u32,
+    },
+}
+
+/// Send the operations rebuilding `source` from the basis described by `signature`
+///
+/// Return the number of bytes read from `source` and their digest.
+pub async fn send_delta<W>(
+    source: File,
+    signature: Signature,
+    progress: &mut ProgressTracker,
+    write: &mut W,
+) -> Result<(u64, blake3::Hash), FileTransferError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let source = source.into_std().await;
+    let (ops_tx, mut ops_rx) = mpsc::channel(16);
+    let (block_len, basis_len) = (signature.block_len, signature.basis_len);
+    let generator = tokio::task::spawn_blocking(move || generate(source, &signature, ops_tx));
+
+    while let Some(op) = ops_rx.recv().await {
+        match op {
+            DeltaOp::Literal(data) => {
+                write.write_u8(LITERAL).await?;
+                write.write_u32(data.len() as u32).await?;
+                write.write_all(&data).await?;
+                progress.add(data.len() as u64);
+            }
+            DeltaOp::Copy { index, count } => {
+                write.write_u8(COPY).await?;
+                write.write_u32(index).await?;
+                write.write_u32(count).await?;
+                let start = u64::from(index) * u64::from(block_len);
+                let end = (u64::from(index + count) * u64::from(block_len)).min(basis_len);
+                progress.add(end - start);
+            }
+        }
+    }
+    write.write_u8(END).await?;
+
+    Ok(generator.await.map_err(io::Error::from)??)
+}
+
+/// Rebuild the source file into `file` from the operations sent by [`send_delta`]
+///
+/// Return the number of bytes written to `file` and their digest.
+pub async fn receive_delta<R>(
+    read: &mut R,
+    signature: &Signature,
+    mut basis: Option<File>,
+    bytes: u64,
+    file: &mut File,
+    progress: &mut ProgressTracker,
+) -> Result<(u64, blake3::Hash), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = blake3::Hasher::new();
+    let mut written = 0;
+    let mut buf = vec![];
+    loop {
+        match read.read_u8().await? {
+            END => break,
+            LITERAL => {
+                let len = read.read_u32().await?;
+                if MAX_LITERAL_LEN < len {
+                    return Err(FileTransferError::protocol_violation(format!(
+                        "literal too long: {len} bytes"
+                    )));
+                }
+                buf.resize(len as usize, 0);
+                read.read_exact(&mut buf).await?;
+                written += u64::from(len);
+                if bytes < written {
+                    return Err(FileTransferError::protocol_violation(
+                        "delta longer than announced",
+                    ));
+                }
+                hasher.update(&buf);
+                file.write_all(&buf).await?;
+                progress.add(u64::from(len));
+            }
+            COPY => {
+                let index = read.read_u32().await?;
+                let count = read.read_u32().await?;
+                let end = index.checked_add(count);
+                let basis = match (&mut basis, end) {
+                    (Some(basis), Some(end)) if end as usize <= signature.blocks.len() => basis,
+                    _ => {
+                        return Err(FileTransferError::protocol_violation(format!(
+                            "invalid block reference: {index}+{count}"
+                        )))
+                    }
+                };
+                for index in index..index + count {
+                    let (offset, len) = signature.block(index);
+                    buf.resize(len, 0);
+                    basis.seek(SeekFrom::Start(offset)).await?;
+                    basis.read_exact(&mut buf).await?;
+                    written += len as u64;
+                    if bytes < written {
+                        return Err(FileTransferError::protocol_violation(
+                            "delta longer than announced",
+                        ));
+                    }
+                    hasher.update(&buf);
+                    file.write_all(&buf).await?;
+                    progress.add(len as u64);
+                }
+            }
+            op => {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "invalid delta operation: {op}"
+                )))
+            }
+        }
+    }
+    file.flush().await?;
+    Ok((written, hasher.finalize()))
+}
+
+/// Slide a block-sized window over `source` and turn it into literals and references to matching blocks
+fn generate(
+    mut source: fs::File,
+    signature: &Signature,
+    ops: mpsc::Sender<DeltaOp>,
+) -> io::Result<(u64, blake3::Hash)> {
+    let send = |op| {
+        ops.blocking_send(op)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "delta sender dropped"))
+    };
+    let block_len = signature.block_len as usize;
+    let mut lookup: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        lookup.entry(block.weak).or_default().push(index as u32);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    let mut read_bytes = 0;
+    let mut data = vec![];
+    let mut start = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut literal = vec![];
+    let mut copy: Option<(u32, u32)> = None;
+    loop {
+        // Keep a whole window buffered unless the source is exhausted
+        while !eof && data.len() - start < block_len {
+            if READ_LEN <= start {
+                data.drain(..start);
+                start = 0;
+            }
+            let filled = data.len();
+            data.resize(filled + READ_LEN, 0);
+            let n = source.read(&mut data[filled..])?;
+            data.truncate(filled + n);
+            hasher.update(&data[filled..]);
+            read_bytes += n as u64;
+            eof = n == 0;
+        }
+        let end = (start + block_len).min(data.len());
+        if start == end {
+            break;
+        }
+        let window = &data[start..end];
+        let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+
+        if let Some(index) = find_block(&lookup, signature, weak.digest(), window) {
+            if !literal.is_empty() {
+                send(DeltaOp::Literal(mem::take(&mut literal)))?;
+            }
+            copy = match copy {
+                Some((first, count)) if first + count == index => Some((first, count + 1)),
+                Some((first, count)) => {
+                    send(DeltaOp::Copy {
+                        index: first,
+                        count,
+                    })?;
+                    Some((index, 1))
+                }
+                None => Some((index, 1)),
+            };
+            start = end;
+            rolling = None;
+            continue;
+        }
+
+        if let Some((index, count)) = copy.take() {
+            send(DeltaOp::Copy { index, count })?;
+        }
+        if window.len() < block_len {
+            // Only the tail of the source is left
+            literal.extend_from_slice(window);
+            start = end;
+        } else {
+            let out = data[start];
+            literal.push(out);
+            match data.get(end) {
+                Some(&input) => rolling = rolling.map(|rolling| rolling.roll(out, input)),
+                None => rolling = None,
+            }
+            start += 1;
+        }
+        if MAX_LITERAL_LEN as usize <= literal.len() {
+            send(DeltaOp::Literal(mem::take(&mut literal)))?;
+        }
+    }
+    if let Some((index, count)) = copy {
+        send(DeltaOp::Copy { index, count })?;
+    }
+    if !literal.is_empty() {
+        send(DeltaOp::Literal(literal))?;
+    }
+    Ok((read_bytes, hasher.finalize()))
+}
+
+fn find_block(
+    lookup: &HashMap<u32, Vec<u32>>,
+    signature: &Signature,
+    weak: u32,
+    window: &[u8],
+) -> Option<u32> {
+    let candidates = lookup.get(&weak)?;
+    let strong = strong(window);
+    candidates.iter().copied().find(|&index| {
+        signature.block(index).1 == window.len()
+            && signature.blocks[index as usize].strong == strong
+    })
+}
+
+fn strong(block: &[u8]) -> [u8; STRONG_LEN] {
+    let mut strong = [0; STRONG_LEN];
+    strong.copy_from_slice(&blake3::hash(block).as_bytes()[..STRONG_LEN]);
+    strong
+}
+
+/// The weak checksum of rsync, cheap to slide one byte along
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(u32::from(byte));
+            b = b.wrapping_add((len - i as u32).wrapping_mul(u32::from(byte)));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(self, out: u8, input: u8) -> Self {
+        let a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(input));
+        let b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(out)))
+            .wrapping_add(a);
+        Self {
+            a,
+            b,
+            len: self.len,
+        }
+    }
+
+    fn digest(self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Fill `buf` unless the end of `read` comes first and return how much was read
+fn read_full<R: Read>(read: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that do not repeat at block scale
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Rebuild `source` from `basis` through the delta protocol
+    ///
+    /// Return the rebuilt file and the length of the delta.
+    async fn round_trip(basis: Option<&[u8]>, source: &[u8]) -> (Vec<u8>, usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let basis_path = dir.path().join("basis");
+        let source_path = dir.path().join("source");
+        let output_path = dir.path().join("output");
+        std::fs::write(&source_path, source).unwrap();
+        let (signature, basis) = match basis {
+            Some(basis) => {
+                std::fs::write(&basis_path, basis).unwrap();
+                let basis = File::open(&basis_path).await.unwrap();
+                (Signature::compute(&basis).await.unwrap(), Some(basis))
+            }
+            None => (Signature::empty(), None),
+        };
+
+        let mut sent_signature = vec![];
+        signature.write_to(&mut sent_signature).await.unwrap();
+        let received_signature = Signature::read_from(&mut &sent_signature[..])
+            .await
+            .unwrap();
+
+        let mut progress = ProgressTracker::new(None, 0);
+        let mut delta = vec![];
+        let source_file = File::open(&source_path).await.unwrap();
+        let (read_bytes, hash) =
+            send_delta(source_file, received_signature, &mut progress, &mut delta)
+                .await
+                .unwrap();
+        assert_eq!(read_bytes, source.len() as u64);
+        assert_eq!(hash, blake3::hash(source));
+
+        let mut output = File::create(&output_path).await.unwrap();
+        let (written, hash) = receive_delta(
+            &mut &delta[..],
+            &signature,
+            basis,
+            source.len() as u64,
+            &mut output,
+            &mut progress,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, source.len() as u64);
+        assert_eq!(hash, blake3::hash(source));
+        (std::fs::read(&output_path).unwrap(), delta.len())
+    }
+
+    #[test]
+    fn roll_matches_recomputing_the_window() {
+        let data = noise(3 * MIN_BLOCK_LEN as usize, 1);
+        let len = MIN_BLOCK_LEN as usize;
+        let mut rolling = Rolling::new(&data[..len]);
+        for start in 1..data.len() - len {
+            rolling = rolling.roll(data[start - 1], data[start - 1 + len]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + len]).digest(),
+                "window at {start}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn insertions_and_deletions() {
+        let basis = noise(256 * 1024, 2);
+        let mut source = basis[..50_000].to_vec();
+        source.extend_from_slice(b"inserted in the middle of a block");
+        source.extend_from_slice(&basis[50_000..100_000]);
+        source.extend_from_slice(&basis[120_000..200_000]);
+        source.extend_from_slice(&noise(5_000, 3));
+        source.extend_from_slice(&basis[210_000..]);
+
+        let (output, delta_len) = round_trip(Some(&basis), &source).await;
+        assert_eq!(output, source);
+        assert!(
+            delta_len < source.len() / 4,
+            "delta of {delta_len} bytes for {} bytes",
+            source.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn short_tail_block() {
+        let basis = noise(10 * MIN_BLOCK_LEN as usize + 100, 4);
+
+        let (output, delta_len) = round_trip(Some(&basis), &basis).await;
+        assert_eq!(output, basis);
+        // A single reference covers every block including the short tail
+        assert_eq!(delta_len, 1 + 4 + 4 + 1);
+
+        let shorter = &basis[..basis.len() - 50];
+        let (output, _) = round_trip(Some(&basis), shorter).await;
+        assert_eq!(output, shorter);
+
+        let mut longer = basis.clone();
+        longer.extend_from_slice(b"appended");
+        let (output, _) = round_trip(Some(&basis), &longer).await;
+        assert_eq!(output, longer);
+    }
+
+    #[tokio::test]
+    async fn empty_basis() {
+        let source = noise(100_000, 5);
+        let (output, _) = round_trip(None, &source).await;
+        assert_eq!(output, source);
+        let (output, _) = round_trip(Some(&[]), &source).await;
+        assert_eq!(output, source);
+        let (output, _) = round_trip(Some(&[]), &[]).await;
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn out_of_range_copies_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let basis_path = dir.path().join("basis");
+        std::fs::write(&basis_path, noise(3 * MIN_BLOCK_LEN as usize, 6)).unwrap();
+        let basis = File::open(&basis_path).await.unwrap();
+        let signature = Signature::compute(&basis).await.unwrap();
+
+        let cases = [
+            (&signature, Some(&basis), 2, 2),
+            (&signature, Some(&basis), 3, 1),
+            (&signature, Some(&basis), u32::MAX, 1),
+            (&Signature::empty(), None, 0, 1),
+        ];
+        for (signature, basis, index, count) in cases {
+            let mut delta = vec![COPY];
+            delta.extend_from_slice(&u32::to_be_bytes(index));
+            delta.extend_from_slice(&u32::to_be_bytes(count));
+            delta.push(END);
+            let basis = match basis {
+                Some(basis) => Some(basis.try_clone().await.unwrap()),
+                None => None,
+            };
+            let mut output = File::create(dir.path().join("output")).await.unwrap();
+            let e = receive_delta(
+                &mut &delta[..],
+                signature,
+                basis,
+                u64::MAX,
+                &mut output,
+                &mut ProgressTracker::new(None, 0),
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(e, FileTransferError::ProtocolViolation(_)),
+                "{index}+{count}: {e}"
+            );
+        }
+    }
+}
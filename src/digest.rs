@@ -0,0 +1,70 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The digest trailing the file content did not match the received content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestMismatchError {
+    pub expected: blake3::Hash,
+    pub actual: blake3::Hash,
+}
+impl core::fmt::Display for DigestMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "digest mismatch: expected {expected}; actual {actual};",
+            expected = self.expected,
+            actual = self.actual,
+        )
+    }
+}
+impl std::error::Error for DigestMismatchError {}
+
+/// Hash and count everything read through it
+pub struct DigestRead<R> {
+    read: R,
+    hasher: blake3::Hasher,
+    bytes: u64,
+}
+impl<R> DigestRead<R> {
+    pub fn new(read: R, hasher: blake3::Hasher) -> Self {
+        Self {
+            read,
+            hasher,
+            bytes: 0,
+        }
+    }
+
+    /// Bytes read through it, excluding what `hasher` was fed with beforehand
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn digest(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.read
+    }
+}
+impl<R> AsyncRead for DigestRead<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.read).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        self.hasher.update(read);
+        self.bytes += read.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
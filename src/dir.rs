@@ -0,0 +1,159 @@
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{sandbox::is_confined, FileTransferError, PreserveArgs};
+
+const MAX_PATH_LEN: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Dir,
+    File,
+}
+
+/// A directory or regular file relative to the transferred root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub path: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+}
+
+/// The entries of a directory tree with every directory listed before its content
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+impl Manifest {
+    /// Collect the directories and regular files under `root`
+    ///
+    /// Symlinks and special files are left out.
+    pub async fn walk(root: &Path) -> io::Result<Self> {
+        let mut entries = vec![];
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let mut read_dir = tokio::fs::read_dir(root.join(&dir)).await?;
+            let mut children = vec![];
+            while let Some(child) = read_dir.next_entry().await? {
+                children.push(child);
+            }
+            children.sort_by_key(|child| child.file_name());
+
+            for child in children {
+                let path = dir.join(child.file_name());
+                let metadata = child.metadata().await?;
+                let kind = if metadata.is_dir() {
+                    dirs.push(path.clone());
+                    EntryKind::Dir
+                } else if metadata.is_file() {
+                    EntryKind::File
+                } else {
+                    continue;
+                };
+                let size = match kind {
+                    EntryKind::Dir => 0,
+                    EntryKind::File => metadata.len(),
+                };
+                entries.push(ManifestEntry {
+                    kind,
+                    path,
+                    size,
+                    mode: metadata.permissions().mode(),
+                });
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind == EntryKind::File)
+    }
+
+    /// Sum of the sizes of all files
+    pub fn total_size(&self) -> u64 {
+        self.files().map(|entry| entry.size).sum()
+    }
+
+    pub async fn write_to<W>(&self, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u64(self.entries.len() as u64).await?;
+        for entry in &self.entries {
+            let kind = match entry.kind {
+                EntryKind::Dir => 0,
+                EntryKind::File => 1,
+            };
+            let path = entry.path.as_os_str().as_bytes();
+            let path_len = u32::try_from(path.len())
+                .ok()
+                .filter(|len| *len <= MAX_PATH_LEN)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path too long"))?;
+            write.write_u8(kind).await?;
+            write.write_u32(entry.mode).await?;
+            write.write_u64(entry.size).await?;
+            write.write_u32(path_len).await?;
+            write.write_all(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Fail if any path would escape the root directory
+    pub async fn read_from<R>(read: &mut R) -> Result<Self, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let count = read.read_u64().await?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let kind = match read.read_u8().await? {
+                0 => EntryKind::Dir,
+                1 => EntryKind::File,
+                kind => {
+                    return Err(FileTransferError::protocol_violation(format!(
+                        "invalid entry kind: {kind}"
+                    )))
+                }
+            };
+            let mode = read.read_u32().await?;
+            let size = read.read_u64().await?;
+            let path_len = read.read_u32().await?;
+            if MAX_PATH_LEN < path_len {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "path too long: {path_len} bytes"
+                )));
+            }
+            let mut path = vec![0; path_len as usize];
+            read.read_exact(&mut path).await?;
+            let path = PathBuf::from(OsStr::from_bytes(&path));
+            if !is_confined(&path) {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "invalid entry path: {}",
+                    path.display()
+                )));
+            }
+            entries.push(ManifestEntry {
+                kind,
+                path,
+                size,
+                mode,
+            });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Apply the bits of the pushed `mode` that `preserve` allows to `path`
+pub async fn set_mode(path: &Path, mode: u32, preserve: &PreserveArgs) -> io::Result<()> {
+    let mode = preserve.permission_bits(mode);
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await
+}
@@ -0,0 +1,115 @@
+use std::io;
+
+use crate::{DigestMismatchError, Rejection};
+
+#[derive(Debug)]
+pub enum FileTransferError {
+    /// Failure of the file system or the transport
+    Io(io::Error),
+    /// The peer closed the connection before the transfer completed
+    UnexpectedEof,
+    /// The peer sent a message that does not follow the protocol
+    ProtocolViolation(String),
+    /// The peers cannot agree on the protocol version or features
+    Incompatible(String),
+    /// One of the peers failed to prove knowledge of the pre-shared key
+    Unauthenticated(String),
+    /// The serving peer turned down the requested path
+    Rejected { reason: Rejection, message: String },
+    /// The source file changed size while being sent
+    FileChanged { expected: u64, actual: u64 },
+    /// A length does not fit in a `usize` on this platform
+    SizeOverflow(u64),
+    /// The received content does not match the digest sent by the pusher
+    DigestMismatch(DigestMismatchError),
+}
+impl FileTransferError {
+    pub(crate) fn protocol_violation(message: impl Into<String>) -> Self {
+        Self::ProtocolViolation(message.into())
+    }
+
+    pub(crate) fn incompatible(message: impl Into<String>) -> Self {
+        Self::Incompatible(message.into())
+    }
+
+    pub(crate) fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::Unauthenticated(message.into())
+    }
+}
+impl core::fmt::Display for FileTransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileTransferError::Io(e) => write!(f, "{e}"),
+            FileTransferError::UnexpectedEof => write!(f, "connection closed by peer"),
+            FileTransferError::ProtocolViolation(message) => {
+                write!(f, "protocol violation: {message}")
+            }
+            FileTransferError::Incompatible(message) => write!(f, "incompatible peer: {message}"),
+            FileTransferError::Unauthenticated(message) => {
+                write!(f, "authentication failed: {message}")
+            }
+            FileTransferError::Rejected { reason, message } => {
+                write!(f, "request rejected: {reason}: {message}")
+            }
+            FileTransferError::FileChanged { expected, actual } => write!(
+                f,
+                "file modified during transmission: expected {expected} bytes; read {actual} bytes;"
+            ),
+            FileTransferError::SizeOverflow(bytes) => write!(f, "size overflow: {bytes} bytes"),
+            FileTransferError::DigestMismatch(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for FileTransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileTransferError::Io(e) => Some(e),
+            FileTransferError::DigestMismatch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for FileTransferError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::UnexpectedEof,
+            _ => Self::Io(e),
+        }
+    }
+}
+impl From<DigestMismatchError> for FileTransferError {
+    fn from(e: DigestMismatchError) -> Self {
+        Self::DigestMismatch(e)
+    }
+}
+impl From<FileTransferError> for io::Error {
+    fn from(e: FileTransferError) -> Self {
+        let kind = match &e {
+            FileTransferError::Io(e) => e.kind(),
+            FileTransferError::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            FileTransferError::ProtocolViolation(_)
+            | FileTransferError::FileChanged { .. }
+            | FileTransferError::DigestMismatch(_) => io::ErrorKind::InvalidData,
+            FileTransferError::Incompatible(_) => io::ErrorKind::Unsupported,
+            FileTransferError::Unauthenticated(_)
+            | FileTransferError::Rejected {
+                reason: Rejection::Forbidden,
+                ..
+            } => io::ErrorKind::PermissionDenied,
+            FileTransferError::Rejected {
+                reason: Rejection::NotFound,
+                ..
+            } => io::ErrorKind::NotFound,
+            FileTransferError::SizeOverflow(_) => io::ErrorKind::OutOfMemory,
+        };
+        match e {
+            FileTransferError::Io(e) => e,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
+
+/// Convert a length received from the peer or read from the file system
+pub(crate) fn to_usize(bytes: u64) -> Result<usize, FileTransferError> {
+    usize::try_from(bytes).map_err(|_| FileTransferError::SizeOverflow(bytes))
+}
@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    chunked::{write_chunk, MAX_CHUNK_LEN},
+    progress::ProgressTracker,
+    FileTransferError,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Send the rest of `file` in chunks and keep sending whatever is appended to it
+///
+/// The end marker is sent once the file has not grown for `idle`.
+/// What was sent is flushed whenever the end of the file is reached so that the puller keeps up.
+///
+/// Return the number of bytes read from `file` and `hasher` fed with them.
+pub async fn send_follow<W>(
+    file: &mut File,
+    idle: Duration,
+    mut hasher: blake3::Hasher,
+    buffer_size: usize,
+    progress: &mut ProgressTracker,
+    write: &mut W,
+) -> Result<(u64, blake3::Hasher), FileTransferError>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Each read goes out as one chunk
+    let mut buf = vec![0; buffer_size.min(MAX_CHUNK_LEN as usize)];
+    let mut position = file.stream_position().await?;
+    let mut read_bytes = 0;
+    let mut idle_since = None;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n != 0 {
+            hasher.update(&buf[..n]);
+            write_chunk(write, &buf[..n]).await?;
+            progress.add(n as u64);
+            position += n as u64;
+            read_bytes += n as u64;
+            idle_since = None;
+            continue;
+        }
+
+        let len = file.metadata().await?.len();
+        if len < position {
+            // Truncated, as by log rotation; the content already sent cannot be taken back
+            return Err(FileTransferError::FileChanged {
+                expected: position,
+                actual: len,
+            });
+        }
+        let since = match idle_since {
+            Some(since) => since,
+            None => {
+                write.flush().await?;
+                *idle_since.insert(Instant::now())
+            }
+        };
+        if idle <= since.elapsed() {
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL.min(idle)).await;
+    }
+    write_chunk(write, &[]).await?;
+
+    Ok((read_bytes, hasher))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::chunked::ChunkedRead;
+
+    #[tokio::test]
+    async fn buffers_longer_than_a_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let content: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let mut file = File::open(&path).await.unwrap();
+        let mut progress = ProgressTracker::new(None, 0);
+        let mut sent = vec![];
+        let (read_bytes, hasher) = send_follow(
+            &mut file,
+            Duration::from_millis(10),
+            blake3::Hasher::new(),
+            64 * 1024 * 1024,
+            &mut progress,
+            &mut sent,
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_bytes, content.len() as u64);
+        assert_eq!(hasher.finalize(), blake3::hash(&content));
+
+        let mut received = vec![];
+        ChunkedRead::new(std::io::Cursor::new(sent))
+            .into_async_read()
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        assert_eq!(received, content);
+    }
+}
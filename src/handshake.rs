@@ -0,0 +1,462 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Compression, FileTransferError};
+
+const MAGIC: [u8; 4] = *b"FTXF";
+const VERSION: u16 = 1;
+
+/// Optional protocol features
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+impl Capabilities {
+    pub const RESUME: Self = Self(1 << 0);
+    pub const RECURSIVE: Self = Self(1 << 1);
+    pub const ZSTD: Self = Self(1 << 2);
+    pub const LZ4: Self = Self(1 << 3);
+    pub const GZIP: Self = Self(1 << 4);
+    pub const XATTRS: Self = Self(1 << 5);
+    pub const AUTH: Self = Self(1 << 6);
+    pub const RANGES: Self = Self(1 << 7);
+    pub const DELTA: Self = Self(1 << 8);
+    pub const SKIP_UNCHANGED: Self = Self(1 << 9);
+    pub const SPARSE: Self = Self(1 << 10);
+    pub const CHUNKED: Self = Self(1 << 11);
+    /// This peer names a remote path once authenticated
+    pub const PATH_REQUEST: Self = Self(1 << 12);
+    /// This peer is a daemon waiting to be named a remote path
+    pub const DAEMON: Self = Self(1 << 13);
+    /// The puller writes to stdout, which holds nothing to resume, compare or seek in
+    pub const STDOUT: Self = Self(1 << 14);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Every feature this build knows about
+    pub const fn supported() -> Self {
+        Self(
+            Self::RESUME.0
+                | Self::RECURSIVE.0
+                | Self::ZSTD.0
+                | Self::LZ4.0
+                | Self::GZIP.0
+                | Self::XATTRS.0
+                | Self::AUTH.0
+                | Self::RANGES.0
+                | Self::DELTA.0
+                | Self::SKIP_UNCHANGED.0
+                | Self::SPARSE.0
+                | Self::CHUNKED.0
+                | Self::PATH_REQUEST.0
+                | Self::DAEMON.0
+                | Self::STDOUT.0,
+        )
+    }
+
+    pub const fn compression(compression: Compression) -> Self {
+        match compression {
+            Compression::Zstd => Self::ZSTD,
+            Compression::Lz4 => Self::LZ4,
+            Compression::Gzip => Self::GZIP,
+        }
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn set(&mut self, other: Self, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+impl core::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Push,
+    Pull,
+}
+impl Role {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Role::Push => 0,
+            Role::Pull => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Role::Push,
+            1 => Role::Pull,
+            _ => return None,
+        })
+    }
+}
+
+/// The first message each peer sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub role: Role,
+    /// Features this peer is able to use
+    pub capabilities: Capabilities,
+    /// Features this peer wants to use for this transfer
+    pub requested: Capabilities,
+}
+impl Hello {
+    pub fn new(role: Role, requested: Capabilities) -> Self {
+        Self {
+            role,
+            capabilities: Capabilities::supported(),
+            requested,
+        }
+    }
+
+    async fn write_to<W>(&self, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_all(&MAGIC).await?;
+        write.write_u16(VERSION).await?;
+        write.write_u8(self.role.to_u8()).await?;
+        write.write_u32(self.capabilities.0).await?;
+        write.write_u32(self.requested.0).await?;
+        write.flush().await?;
+        Ok(())
+    }
+
+    async fn read_from<R>(read: &mut R) -> Result<Self, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut magic = [0; MAGIC.len()];
+        read.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(FileTransferError::incompatible(
+                "peer is not speaking the file transfer protocol",
+            ));
+        }
+        let version = read.read_u16().await?;
+        if version != VERSION {
+            return Err(FileTransferError::incompatible(format!(
+                "unsupported protocol version: local {VERSION}; peer {version};"
+            )));
+        }
+        let role = Role::from_u8(read.read_u8().await?)
+            .ok_or_else(|| FileTransferError::protocol_violation("invalid peer role"))?;
+        let capabilities = Capabilities(read.read_u32().await?);
+        let requested = Capabilities(read.read_u32().await?);
+        Ok(Self {
+            role,
+            capabilities,
+            requested,
+        })
+    }
+}
+
+/// Exchange hellos with the peer and return the features both peers agreed to use
+pub async fn handshake<R, W>(
+    read: &mut R,
+    write: &mut W,
+    local: Hello,
+) -> Result<Capabilities, FileTransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    local.write_to(write).await?;
+    let peer = Hello::read_from(read).await?;
+
+    if peer.role == local.role {
+        let role = match local.role {
+            Role::Push => "pushing",
+            Role::Pull => "pulling",
+        };
+        return Err(FileTransferError::incompatible(format!(
+            "both peers are {role}"
+        )));
+    }
+    if !local.capabilities.contains(peer.requested) {
+        return Err(FileTransferError::incompatible(format!(
+            "peer requested unsupported features: {:#x}",
+            peer.requested.0 & !local.capabilities.0
+        )));
+    }
+    if !peer.capabilities.contains(local.requested) {
+        return Err(FileTransferError::incompatible(format!(
+            "peer does not support requested features: {:#x}",
+            local.requested.0 & !peer.capabilities.0
+        )));
+    }
+
+    if local.requested.contains(Capabilities::PATH_REQUEST)
+        && !peer.requested.contains(Capabilities::DAEMON)
+    {
+        return Err(FileTransferError::incompatible(
+            "peer serves a single path and takes no remote path",
+        ));
+    }
+    if local.requested.contains(Capabilities::DAEMON)
+        && !peer.requested.contains(Capabilities::PATH_REQUEST)
+    {
+        return Err(FileTransferError::incompatible(
+            "peer names no remote path for the daemon to transfer",
+        ));
+    }
+    if peer.requested.contains(Capabilities::PATH_REQUEST)
+        && !local.requested.contains(Capabilities::DAEMON)
+    {
+        return Err(FileTransferError::incompatible(
+            "peer names a remote path but this side serves a single path",
+        ));
+    }
+    if peer.requested.contains(Capabilities::DAEMON)
+        && !local.requested.contains(Capabilities::PATH_REQUEST)
+    {
+        return Err(FileTransferError::incompatible(
+            "peer is a daemon and needs a remote path",
+        ));
+    }
+
+    let features = local.requested | peer.requested;
+    if features.contains(Capabilities::RESUME | Capabilities::RECURSIVE) {
+        return Err(FileTransferError::incompatible(
+            "resuming directory transfers is not supported",
+        ));
+    }
+    if features.contains(Capabilities::RANGES)
+        && features.intersects(Capabilities::RESUME | Capabilities::RECURSIVE)
+    {
+        return Err(FileTransferError::incompatible(
+            "transferring ranges over several streams only supports whole single files",
+        ));
+    }
+    let not_with_delta = Capabilities::RESUME
+        | Capabilities::RECURSIVE
+        | Capabilities::RANGES
+        | Capabilities::ZSTD
+        | Capabilities::LZ4
+        | Capabilities::GZIP;
+    if features.contains(Capabilities::DELTA) && features.intersects(not_with_delta) {
+        return Err(FileTransferError::incompatible(
+            "delta transfers do not combine with resuming, directories, ranges or compression",
+        ));
+    }
+    if features.contains(Capabilities::SPARSE)
+        && features.intersects(not_with_delta | Capabilities::DELTA)
+    {
+        return Err(FileTransferError::incompatible(
+            "sparse transfers do not combine with resuming, directories, ranges, deltas or compression",
+        ));
+    }
+    if features.contains(Capabilities::SKIP_UNCHANGED)
+        && features.intersects(Capabilities::RECURSIVE | Capabilities::RANGES)
+    {
+        return Err(FileTransferError::incompatible(
+            "skipping unchanged files only supports single files over one stream",
+        ));
+    }
+    let not_chunked = Capabilities::RESUME
+        | Capabilities::RECURSIVE
+        | Capabilities::RANGES
+        | Capabilities::DELTA
+        | Capabilities::SPARSE
+        | Capabilities::SKIP_UNCHANGED;
+    if features.contains(Capabilities::CHUNKED) && features.intersects(not_chunked) {
+        return Err(FileTransferError::incompatible(
+            "content of unknown length only supports plain transfers of single files",
+        ));
+    }
+    let not_to_stdout = Capabilities::RESUME
+        | Capabilities::RECURSIVE
+        | Capabilities::RANGES
+        | Capabilities::DELTA
+        | Capabilities::SPARSE
+        | Capabilities::SKIP_UNCHANGED;
+    if features.contains(Capabilities::STDOUT) && features.intersects(not_to_stdout) {
+        return Err(FileTransferError::incompatible(
+            "writing to stdout only supports plain transfers of single files over one stream",
+        ));
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Outcome = Result<Capabilities, FileTransferError>;
+
+    /// Run the handshake of a peer in `local` role against one in `peer` role
+    async fn shake(local: Hello, peer: Hello) -> (Outcome, Outcome) {
+        let (local_stream, peer_stream) = tokio::io::duplex(1024);
+        let (mut local_read, mut local_write) = tokio::io::split(local_stream);
+        let (mut peer_read, mut peer_write) = tokio::io::split(peer_stream);
+        tokio::join!(
+            handshake(&mut local_read, &mut local_write, local),
+            handshake(&mut peer_read, &mut peer_write, peer),
+        )
+    }
+
+    /// Run the handshake of a peer in `role` against something sending `bytes` instead of a hello
+    async fn shake_with_bytes(role: Role, bytes: &[u8]) -> Outcome {
+        let (local_stream, mut peer_stream) = tokio::io::duplex(1024);
+        peer_stream.write_all(bytes).await.unwrap();
+        let (mut read, mut write) = tokio::io::split(local_stream);
+        handshake(
+            &mut read,
+            &mut write,
+            Hello::new(role, Capabilities::empty()),
+        )
+        .await
+    }
+
+    fn hello_bytes(magic: [u8; 4], version: u16, role: Role) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.push(role.to_u8());
+        bytes.extend_from_slice(&Capabilities::supported().0.to_be_bytes());
+        bytes.extend_from_slice(&Capabilities::empty().0.to_be_bytes());
+        bytes
+    }
+
+    fn peer_role(role: Role) -> Role {
+        match role {
+            Role::Push => Role::Pull,
+            Role::Pull => Role::Push,
+        }
+    }
+
+    #[tokio::test]
+    async fn requested_features_are_combined() {
+        let push = Hello::new(Role::Push, Capabilities::RESUME | Capabilities::ZSTD);
+        let pull = Hello::new(Role::Pull, Capabilities::RESUME | Capabilities::XATTRS);
+        let (pushed, pulled) = shake(push, pull).await;
+        let expected = Capabilities::RESUME | Capabilities::ZSTD | Capabilities::XATTRS;
+        assert_eq!(pushed.unwrap(), expected);
+        assert_eq!(pulled.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn bad_magic_is_rejected() {
+        for role in [Role::Push, Role::Pull] {
+            let bytes = hello_bytes(*b"HTTP", VERSION, peer_role(role));
+            let e = shake_with_bytes(role, &bytes).await.unwrap_err();
+            assert!(matches!(e, FileTransferError::Incompatible(_)), "{e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_is_rejected() {
+        for role in [Role::Push, Role::Pull] {
+            let bytes = hello_bytes(MAGIC, VERSION + 1, peer_role(role));
+            let e = shake_with_bytes(role, &bytes).await.unwrap_err();
+            assert!(
+                matches!(&e, FileTransferError::Incompatible(message) if message.contains("version")),
+                "{e}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn same_roles_are_rejected() {
+        for role in [Role::Push, Role::Pull] {
+            let hello = Hello::new(role, Capabilities::empty());
+            let (local, peer) = shake(hello, hello).await;
+            assert!(matches!(local, Err(FileTransferError::Incompatible(_))));
+            assert!(matches!(peer, Err(FileTransferError::Incompatible(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn exclusive_features_are_rejected_by_both_peers() {
+        let cases = [
+            (Capabilities::DELTA, Capabilities::SPARSE),
+            (Capabilities::DELTA, Capabilities::RANGES),
+            (Capabilities::DELTA, Capabilities::ZSTD),
+            (Capabilities::SPARSE, Capabilities::RANGES),
+            (Capabilities::RESUME, Capabilities::RECURSIVE),
+            (Capabilities::RANGES, Capabilities::RECURSIVE),
+            (Capabilities::SKIP_UNCHANGED, Capabilities::RECURSIVE),
+            (Capabilities::CHUNKED, Capabilities::RESUME),
+            (Capabilities::STDOUT, Capabilities::RESUME),
+            (Capabilities::STDOUT, Capabilities::DELTA),
+            (Capabilities::STDOUT, Capabilities::RANGES),
+        ];
+        for (push, pull) in cases {
+            // Whichever peer asks for which feature
+            for (push, pull) in [(push, pull), (pull, push)] {
+                let (pushed, pulled) =
+                    shake(Hello::new(Role::Push, push), Hello::new(Role::Pull, pull)).await;
+                assert!(
+                    matches!(pushed, Err(FileTransferError::Incompatible(_))),
+                    "pusher accepted {push:?} with {pull:?}"
+                );
+                assert!(
+                    matches!(pulled, Err(FileTransferError::Incompatible(_))),
+                    "puller accepted {pull:?} with {push:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn path_requests_need_a_daemon() {
+        let request = Capabilities::PATH_REQUEST;
+        let daemon = Capabilities::DAEMON;
+        let none = Capabilities::empty();
+        let (pushed, pulled) = shake(
+            Hello::new(Role::Push, daemon),
+            Hello::new(Role::Pull, request),
+        )
+        .await;
+        pushed.unwrap();
+        pulled.unwrap();
+
+        let cases = [
+            (request, none),
+            (none, request),
+            (daemon, none),
+            (none, daemon),
+            (request, request),
+            (daemon, daemon),
+        ];
+        for (push, pull) in cases {
+            let (pushed, pulled) =
+                shake(Hello::new(Role::Push, push), Hello::new(Role::Pull, pull)).await;
+            assert!(
+                matches!(pushed, Err(FileTransferError::Incompatible(_))),
+                "pusher accepted {push:?} with {pull:?}"
+            );
+            assert!(
+                matches!(pulled, Err(FileTransferError::Incompatible(_))),
+                "puller accepted {pull:?} with {push:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_features_are_rejected() {
+        let unknown = Capabilities(1 << 31);
+        let push = Hello::new(Role::Push, unknown);
+        let pull = Hello::new(Role::Pull, Capabilities::empty());
+        let (pushed, pulled) = shake(push, pull).await;
+        assert!(matches!(pushed, Err(FileTransferError::Incompatible(_))));
+        assert!(matches!(pulled, Err(FileTransferError::Incompatible(_))));
+    }
+}
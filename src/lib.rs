@@ -1,47 +1,241 @@
 use std::{
-    io,
+    io::SeekFrom,
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+use atomic::PartFile;
+use auth::{authenticate, read_psk};
+use chunked::{write_chunks, ChunkedRead};
 use clap::{Args, Subcommand};
+pub use compression::Compression;
+use counter::Counted;
+use daemon::receive_request;
+pub use daemon::{daemon, DaemonArgs, DaemonEvent};
+use delta::{receive_delta, send_delta, Signature};
+pub use digest::DigestMismatchError;
+use digest::DigestRead;
+use dir::{EntryKind, Manifest};
+use error::to_usize;
+pub use error::FileTransferError;
+use follow::send_follow;
+use handshake::{handshake, Capabilities, Hello, Role};
+use metadata::FileMetadata;
+pub use metadata::PreserveArgs;
+pub use progress::{display_progress, Progress};
+use progress::{spawn_progress_bar, ProgressTracker, Tracked};
+use rate_limit::{token_bucket, RateLimited, TokenBucket};
 use read_exact::ReadExact;
+pub use request::Rejection;
+use request::{send_request, PathRequest};
+use resume::ResumeRequest;
+pub use sandbox::Sandbox;
+use sparse::{receive_sparse, send_sparse};
+pub use tcp::{connect, connect_parallel, serve, serve_parallel};
+pub use tls::{ClientTlsArgs, ServerTlsArgs};
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::watch,
 };
+pub use tuning::TuningArgs;
+use unchanged::{read_verdict, write_verdict, Fingerprint};
+pub use uring::UringArgs;
+use zero_copy::{hash_file, Socket};
 
+mod atomic;
+mod auth;
+mod chunked;
+mod compression;
+mod counter;
+mod daemon;
+mod delta;
+mod digest;
+mod dir;
+mod error;
+mod follow;
+mod handshake;
+mod metadata;
+mod parallel;
+mod progress;
+mod rate_limit;
 mod read_exact;
+mod request;
+mod resume;
+mod sandbox;
+mod sparse;
+mod tcp;
+mod timeout;
+mod tls;
+mod tuning;
+mod unchanged;
+mod uring;
+mod zero_copy;
 
 const CLOSE: u8 = 0;
+const DEFAULT_FOLLOW_IDLE: u64 = 5;
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum FileTransferCommand {
+    /// Send a file or directory tree to the peer
     Push(PushFileArgs),
+    /// Receive a file or directory tree from the peer
     Pull(PullFileArgs),
 }
 
 impl FileTransferCommand {
     pub async fn perform<R, W>(
         &self,
-        mut read: R,
-        mut write: W,
-    ) -> io::Result<FileTransferResult<R, W>>
+        read: R,
+        write: W,
+    ) -> Result<FileTransferResult<R, W>, FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        self.perform_over(read, write, None, None).await
+    }
+
+    /// Same as [`Self::perform`] with the path to transfer agreed on after authenticating
+    pub(crate) async fn perform_request<R, W>(
+        &self,
+        read: R,
+        write: W,
+        request: Option<PathRequest<'_>>,
+    ) -> Result<FileTransferResult<R, W>, FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        self.perform_over(read, write, None, request).await
+    }
+
+    /// Same as [`Self::perform`] over a plain TCP connection
+    ///
+    /// On Linux, uncompressed content of single files that is not rate limited is moved between the file and the socket inside the kernel.
+    pub async fn perform_tcp(
+        &self,
+        stream: TcpStream,
+    ) -> Result<FileTransferResult<OwnedReadHalf, OwnedWriteHalf>, FileTransferError> {
+        self.perform_tcp_request(stream, None).await
+    }
+
+    pub(crate) async fn perform_tcp_request(
+        &self,
+        stream: TcpStream,
+        request: Option<PathRequest<'_>>,
+    ) -> Result<FileTransferResult<OwnedReadHalf, OwnedWriteHalf>, FileTransferError> {
+        #[cfg(target_os = "linux")]
+        {
+            let socket = Socket::new(&stream)?;
+            let (read, write) = stream.into_split();
+            self.perform_over(read, write, Some(&socket), request).await
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (read, write) = stream.into_split();
+            self.perform_over(read, write, None, request).await
+        }
+    }
+
+    /// `socket` is the connection underlying `read` and `write` if they do not buffer
+    ///
+    /// `request`, if any, is carried out between authenticating the peer and the transfer.
+    async fn perform_over<R, W>(
+        &self,
+        read: R,
+        write: W,
+        socket: Option<&Socket>,
+        request: Option<PathRequest<'_>>,
+    ) -> Result<FileTransferResult<R, W>, FileTransferError>
     where
         R: AsyncRead + Unpin + Send + 'static,
         W: AsyncWrite + Unpin,
     {
         let start = Instant::now();
-        let (bytes, read, write) = match self {
-            FileTransferCommand::Push(args) => {
-                let (bytes, write) = args.push_file(write).await?;
+        let psk = self.psk().await?;
+        let mut read = Counted::new(read);
+        let mut write = Counted::new(write);
+        let requested = self.capabilities()
+            | request.map_or(Capabilities::empty(), |request| request.capability());
+        let features = self
+            .establish(&mut read, &mut write, psk.as_deref(), requested)
+            .await?;
+        if features.contains(Capabilities::RANGES) {
+            return Err(FileTransferError::incompatible(
+                "peer transfers ranges over several streams",
+            ));
+        }
+        let command = self.negotiated(features);
+        let command = match request {
+            None => command,
+            Some(PathRequest::Send(path)) => {
+                send_request(&mut read, &mut write, path).await?;
+                command
+            }
+            Some(PathRequest::Receive) => receive_request(&mut read, &mut write, command).await?,
+        };
+        let skipped;
+        let (bytes, read, write) = match command {
+            FileTransferCommand::Push(mut args) => {
+                let progress_bar = args
+                    .progress_bar
+                    .then(|| spawn_progress_bar(&mut args.progress));
+                skipped =
+                    args.skip_unchanged && args.skip_if_unchanged(&mut read, &mut write).await?;
+                let (bytes, mut read, write) = if skipped {
+                    (0, read, write)
+                } else if args.recursive {
+                    let (bytes, write) = args.push_dir(write).await?;
+                    (bytes, read, write)
+                } else if args.resume {
+                    args.push_file_resume(read, write).await?
+                } else if args.delta {
+                    args.push_file_delta(read, write).await?
+                } else {
+                    let (bytes, write) = args.push_file_over(write, socket).await?;
+                    (bytes, read, write)
+                };
+                drop(args);
+                if let Some(progress_bar) = progress_bar {
+                    let _ = progress_bar.await;
+                }
                 let msg = read.read_u8().await?;
-                assert_eq!(msg, CLOSE);
+                if msg != CLOSE {
+                    return Err(FileTransferError::protocol_violation(format!(
+                        "expected close message; got {msg}"
+                    )));
+                }
                 (bytes, read, write)
             }
-            FileTransferCommand::Pull(args) => {
-                let (bytes, read) = args.pull_file(read).await?;
+            FileTransferCommand::Pull(mut args) => {
+                let progress_bar = args
+                    .progress_bar
+                    .then(|| spawn_progress_bar(&mut args.progress));
+                skipped =
+                    args.skip_unchanged && args.skip_if_unchanged(&mut read, &mut write).await?;
+                let (bytes, read, mut write) = if skipped {
+                    (0, read, write)
+                } else if args.recursive {
+                    let (bytes, read) = args.pull_dir(read).await?;
+                    (bytes, read, write)
+                } else if args.resume {
+                    args.pull_file_resume(read, write).await?
+                } else if args.delta {
+                    args.pull_file_delta(read, write).await?
+                } else {
+                    let (bytes, read) = args.pull_file_over(read, socket).await?;
+                    (bytes, read, write)
+                };
+                drop(args);
+                if let Some(progress_bar) = progress_bar {
+                    let _ = progress_bar.await;
+                }
                 write.write_u8(CLOSE).await?;
                 (bytes, read, write)
             }
@@ -50,12 +244,91 @@ impl FileTransferCommand {
         let throughput = bytes as f64 / duration.as_secs_f64();
         let throughput_mib_s = throughput / 1024. / 1024.;
         let latency_ms = duration.as_secs_f64() * 1000.;
+        let kernel_bytes = socket.map_or(0, Socket::bytes);
+        let wire_bytes = to_usize(read.bytes() + write.bytes() + kernel_bytes)?;
+        let wire_throughput_mib_s = wire_bytes as f64 / duration.as_secs_f64() / 1024. / 1024.;
         let stats = FileTransferStats {
             bytes,
+            wire_bytes,
             throughput_mib_s,
+            wire_throughput_mib_s,
             latency_ms,
+            rate_limit: self.rate_limit(),
+            skipped,
         };
-        Ok(FileTransferResult { stats, read, write })
+        Ok(FileTransferResult {
+            stats,
+            read: read.into_inner(),
+            write: write.into_inner(),
+        })
+    }
+
+    /// Whether the content goes to stdout, which then has no room for anything else
+    pub fn writes_stdout(&self) -> bool {
+        match self {
+            FileTransferCommand::Push(_) => false,
+            FileTransferCommand::Pull(args) => args.is_stdout(),
+        }
+    }
+
+    fn role(&self) -> Role {
+        match self {
+            FileTransferCommand::Push(_) => Role::Push,
+            FileTransferCommand::Pull(_) => Role::Pull,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self {
+            FileTransferCommand::Push(args) => args.capabilities(),
+            FileTransferCommand::Pull(args) => args.capabilities(),
+        }
+    }
+
+    /// The command with the features the peers agreed on turned on and the others off
+    fn negotiated(&self, features: Capabilities) -> Self {
+        match self {
+            FileTransferCommand::Push(args) => FileTransferCommand::Push(args.negotiated(features)),
+            FileTransferCommand::Pull(args) => FileTransferCommand::Pull(args.negotiated(features)),
+        }
+    }
+
+    fn rate_limit(&self) -> Option<u64> {
+        match self {
+            FileTransferCommand::Push(args) => args.rate_limit,
+            FileTransferCommand::Pull(args) => args.rate_limit,
+        }
+    }
+
+    async fn psk(&self) -> std::io::Result<Option<Vec<u8>>> {
+        let psk_file = match self {
+            FileTransferCommand::Push(args) => args.psk_file.as_deref(),
+            FileTransferCommand::Pull(args) => args.psk_file.as_deref(),
+        };
+        match psk_file {
+            Some(path) => Ok(Some(read_psk(path).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Exchange hellos and authenticate the peer if either side asks for it
+    async fn establish<R, W>(
+        &self,
+        read: &mut R,
+        write: &mut W,
+        psk: Option<&[u8]>,
+        requested: Capabilities,
+    ) -> Result<Capabilities, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let role = self.role();
+        let features = handshake(read, write, Hello::new(role, requested)).await?;
+        if features.contains(Capabilities::AUTH) {
+            authenticate(read, write, role, psk).await?;
+        }
+        Ok(features)
     }
 }
 
@@ -68,85 +341,1143 @@ pub struct FileTransferResult<R, W> {
 
 #[derive(Debug, Clone, Args)]
 pub struct PushFileArgs {
+    /// File to push or `-` for stdin
     pub source_file: PathBuf,
+    /// Skip the part of the file the puller already has
+    #[arg(long)]
+    pub resume: bool,
+    /// Push the directory tree rooted at `source_file`
+    #[arg(short, long, conflicts_with = "resume")]
+    pub recursive: bool,
+    /// Send only the parts of the file that differ from the puller's existing copy
+    #[arg(long, conflicts_with_all = ["resume", "recursive", "compression"])]
+    pub delta: bool,
+    /// Send nothing if the puller already has an identical file
+    #[arg(long, conflicts_with = "recursive")]
+    pub skip_unchanged: bool,
+    /// Send only the data of a sparse file and let the puller recreate its holes
+    #[arg(long, conflicts_with_all = ["resume", "recursive", "delta", "compression"])]
+    pub sparse: bool,
+    /// Send the content in chunks instead of announcing its length up front
+    ///
+    /// The file may then grow while being sent. Implied by stdin and `--follow`.
+    #[arg(long, conflicts_with_all = ["resume", "recursive", "delta", "skip_unchanged", "sparse"])]
+    pub chunked: bool,
+    /// Keep sending what is appended to the file until it stops growing
+    #[arg(
+        long,
+        conflicts_with_all = ["resume", "recursive", "delta", "skip_unchanged", "sparse", "compression"],
+    )]
+    pub follow: bool,
+    /// Seconds the followed file must not grow for before the transfer ends
+    #[arg(
+        long,
+        default_value_t = DEFAULT_FOLLOW_IDLE,
+        requires = "follow",
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub follow_idle: u64,
+    /// Compress the file content on the wire
+    #[arg(short, long, value_enum)]
+    pub compression: Option<Compression>,
+    /// Send extended attributes along with the other metadata
+    #[arg(short = 'X', long)]
+    pub xattrs: bool,
+    #[command(flatten)]
+    pub tuning: TuningArgs,
+    #[command(flatten)]
+    pub uring: UringArgs,
+    /// Require the peer to prove knowledge of the pre-shared key in this file
+    #[arg(long)]
+    pub psk_file: Option<PathBuf>,
+    /// Limit the transfer to this many bytes per second
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit: Option<u64>,
+    /// Bytes allowed in a burst above the rate limit; defaults to one second worth of traffic
+    #[arg(long, requires = "rate_limit", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit_burst: Option<u64>,
+    /// Show the progress of the transfer on stderr
+    #[arg(long)]
+    pub progress_bar: bool,
+    /// Receive the progress of the transfer
+    #[arg(skip)]
+    pub progress: Option<watch::Sender<Progress>>,
 }
 
 impl PushFileArgs {
-    pub async fn push_file<W>(&self, write: W) -> io::Result<(usize, W)>
+    pub fn new(source_file: impl Into<PathBuf>) -> Self {
+        Self {
+            source_file: source_file.into(),
+            resume: false,
+            recursive: false,
+            delta: false,
+            skip_unchanged: false,
+            sparse: false,
+            chunked: false,
+            follow: false,
+            follow_idle: DEFAULT_FOLLOW_IDLE,
+            compression: None,
+            xattrs: false,
+            tuning: TuningArgs::default(),
+            uring: UringArgs::default(),
+            psk_file: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+            progress_bar: false,
+            progress: None,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::empty();
+        capabilities.set(Capabilities::RESUME, self.resume);
+        capabilities.set(Capabilities::RECURSIVE, self.recursive);
+        capabilities.set(Capabilities::DELTA, self.delta);
+        capabilities.set(Capabilities::SKIP_UNCHANGED, self.skip_unchanged);
+        capabilities.set(Capabilities::SPARSE, self.sparse);
+        capabilities.set(Capabilities::CHUNKED, self.sends_chunks());
+        capabilities.set(Capabilities::XATTRS, self.xattrs);
+        capabilities.set(Capabilities::AUTH, self.psk_file.is_some());
+        if let Some(compression) = self.compression {
+            capabilities.set(Capabilities::compression(compression), true);
+        }
+        capabilities
+    }
+
+    fn negotiated(&self, features: Capabilities) -> Self {
+        Self {
+            resume: features.contains(Capabilities::RESUME),
+            recursive: features.contains(Capabilities::RECURSIVE),
+            delta: features.contains(Capabilities::DELTA),
+            skip_unchanged: features.contains(Capabilities::SKIP_UNCHANGED),
+            sparse: features.contains(Capabilities::SPARSE),
+            chunked: features.contains(Capabilities::CHUNKED),
+            xattrs: features.contains(Capabilities::XATTRS),
+            ..self.clone()
+        }
+    }
+
+    fn token_bucket(&self) -> Option<TokenBucket> {
+        token_bucket(self.rate_limit, self.rate_limit_burst)
+    }
+
+    fn is_stdin(&self) -> bool {
+        self.source_file.as_os_str() == "-"
+    }
+
+    /// Whether the content is sent without its length, which stdin and followed files cannot tell
+    fn sends_chunks(&self) -> bool {
+        self.chunked || self.follow || self.is_stdin()
+    }
+
+    /// Compare the source file with what the puller reports to have and tell it whether to expect the content
+    ///
+    /// Return `true` if the puller's file is identical and nothing more is to be sent.
+    pub async fn skip_if_unchanged<R, W>(
+        &self,
+        read: &mut R,
+        write: &mut W,
+    ) -> Result<bool, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let skip = match Fingerprint::read_from(read).await? {
+            Some(fingerprint) => {
+                let mut file = File::open(&self.source_file).await?;
+                fingerprint.matches(&mut file).await?
+            }
+            None => false,
+        };
+        write_verdict(write, skip).await?;
+        Ok(skip)
+    }
+
+    pub async fn push_file<W>(&self, write: W) -> Result<(usize, W), FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.push_file_over(write, None).await
+    }
+
+    /// Push the content of `source` in place of the file, without knowing its length up front
+    ///
+    /// The content is sent in chunks, so the puller must expect them as it does with `chunked`.
+    pub async fn push_stream<S, W>(
+        &self,
+        source: S,
+        write: W,
+    ) -> Result<(usize, W), FileTransferError>
     where
+        S: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        push_file(&self.source_file, write).await
+        let mut write = RateLimited::new(write, self.token_bucket());
+        let mut progress = ProgressTracker::new(self.progress.clone(), 0);
+        let metadata = FileMetadata::for_stream();
+        let read_bytes = self
+            .send_stream(
+                source,
+                &metadata,
+                blake3::Hasher::new(),
+                &mut progress,
+                &mut write,
+            )
+            .await?;
+        Ok((to_usize(read_bytes)?, write.into_inner()))
+    }
+
+    async fn push_file_over<W>(
+        &self,
+        write: W,
+        socket: Option<&Socket>,
+    ) -> Result<(usize, W), FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.is_stdin() {
+            return self.push_stream(tokio::io::stdin(), write).await;
+        }
+        let mut write = RateLimited::new(write, self.token_bucket());
+        let file = File::open(&self.source_file).await?;
+        let bytes = file.metadata().await?.size();
+        if self.sends_chunks() {
+            // A followed file has no known length to make progress towards
+            let total = if self.follow { 0 } else { bytes };
+            let mut progress = ProgressTracker::new(self.progress.clone(), total);
+            let read_bytes = self
+                .send_content(file, blake3::Hasher::new(), &mut progress, &mut write)
+                .await?;
+            return Ok((to_usize(read_bytes)?, write.into_inner()));
+        }
+
+        write.write_u64(bytes).await?;
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes);
+        let read_bytes = match self.sparse {
+            true => {
+                let metadata = FileMetadata::read(&file, self.xattrs).await?;
+                let (read_bytes, digest) =
+                    send_sparse(file, bytes, &mut progress, &mut write).await?;
+                write.write_all(digest.as_bytes()).await?;
+                metadata.write_to(&mut write).await?;
+                read_bytes
+            }
+            false => {
+                match socket.filter(|_| self.compression.is_none() && self.rate_limit.is_none()) {
+                    Some(socket) => {
+                        self.send_content_zero_copy(file, bytes, socket, &mut progress, &mut write)
+                            .await?
+                    }
+                    None => {
+                        self.send_content(file, blake3::Hasher::new(), &mut progress, &mut write)
+                            .await?
+                    }
+                }
+            }
+        };
+
+        check_unchanged(bytes, read_bytes)?;
+
+        Ok((to_usize(read_bytes)?, write.into_inner()))
+    }
+
+    /// Push only the part of the file following the prefix the puller reports to already have
+    ///
+    /// The whole file is sent if the puller's prefix does not match the source file.
+    pub async fn push_file_resume<R, W>(
+        &self,
+        mut read: R,
+        write: W,
+    ) -> Result<(usize, R, W), FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut write = RateLimited::new(write, self.token_bucket());
+        let mut file = File::open(&self.source_file).await?;
+        let bytes = file.metadata().await?.size();
+
+        let request = ResumeRequest::read_from(&mut read).await?;
+        let (offset, hasher) = request.resume_offset(&mut file).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        // The file may have grown past its measured length while the prefix was checked
+        let remaining = bytes
+            .checked_sub(offset)
+            .ok_or(FileTransferError::FileChanged {
+                expected: bytes,
+                actual: offset,
+            })?;
+
+        write.write_u64(bytes).await?;
+        write.write_u64(offset).await?;
+        let mut progress = ProgressTracker::new(self.progress.clone(), remaining);
+        let read_bytes = self
+            .send_content(file, hasher, &mut progress, &mut write)
+            .await?;
+
+        check_unchanged(remaining, read_bytes)?;
+
+        Ok((to_usize(read_bytes)?, read, write.into_inner()))
+    }
+
+    /// Push the file as literal data and references to the blocks the puller reports to already have
+    pub async fn push_file_delta<R, W>(
+        &self,
+        mut read: R,
+        write: W,
+    ) -> Result<(usize, R, W), FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut write = RateLimited::new(write, self.token_bucket());
+        let file = File::open(&self.source_file).await?;
+        let bytes = file.metadata().await?.size();
+        let metadata = FileMetadata::read(&file, self.xattrs).await?;
+
+        let signature = Signature::read_from(&mut read).await?;
+        write.write_u64(bytes).await?;
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes);
+        let (read_bytes, digest) = send_delta(file, signature, &mut progress, &mut write).await?;
+        write.write_all(digest.as_bytes()).await?;
+        metadata.write_to(&mut write).await?;
+
+        check_unchanged(bytes, read_bytes)?;
+
+        Ok((to_usize(read_bytes)?, read, write.into_inner()))
+    }
+
+    /// Push a manifest of the directory tree followed by the content of each of its files
+    pub async fn push_dir<W>(&self, write: W) -> Result<(usize, W), FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut write = RateLimited::new(write, self.token_bucket());
+        let manifest = Manifest::walk(&self.source_file).await?;
+        manifest.write_to(&mut write).await?;
+
+        let mut progress = ProgressTracker::new(self.progress.clone(), manifest.total_size());
+        let mut total_bytes = 0;
+        for entry in manifest.files() {
+            let file = File::open(self.source_file.join(&entry.path)).await?;
+            let read_bytes = self
+                .send_content(file, blake3::Hasher::new(), &mut progress, &mut write)
+                .await?;
+
+            check_unchanged(entry.size, read_bytes)?;
+
+            total_bytes += read_bytes;
+        }
+
+        Ok((to_usize(total_bytes)?, write.into_inner()))
+    }
+
+    /// Copy the rest of `file` to `write` followed by the digest of the whole file and its metadata
+    ///
+    /// `hasher` is expected to have been fed with the part of the file before the cursor.
+    ///
+    /// Return the number of bytes read from `file`.
+    async fn send_content<W>(
+        &self,
+        mut file: File,
+        hasher: blake3::Hasher,
+        progress: &mut ProgressTracker,
+        write: &mut W,
+    ) -> Result<u64, FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.follow {
+            return self
+                .send_content_follow(file, hasher, progress, write)
+                .await;
+        }
+        let metadata = FileMetadata::read(&file, self.xattrs).await?;
+        // The ring only reads as far as the file reaches now, which would cut off chunks of a growing file
+        let uring = match self.sends_chunks() {
+            true => None,
+            false => {
+                let offset = file.stream_position().await?;
+                let len = file.metadata().await?.len().saturating_sub(offset);
+                self.uring.reader(&file, offset, len).await?
+            }
+        };
+        match uring {
+            Some(read) => {
+                self.send_stream(read, &metadata, hasher, progress, write)
+                    .await
+            }
+            None => {
+                self.send_stream(file, &metadata, hasher, progress, write)
+                    .await
+            }
+        }
+    }
+
+    /// Same as [`Self::send_content`] without compression but with the first `bytes` bytes of `file` sent to `socket` by the kernel
+    async fn send_content_zero_copy<W>(
+        &self,
+        file: File,
+        bytes: u64,
+        socket: &Socket,
+        progress: &mut ProgressTracker,
+        write: &mut W,
+    ) -> Result<u64, FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let metadata = FileMetadata::read(&file, self.xattrs).await?;
+        write.write_u8(Compression::codec(None)).await?;
+        write.flush().await?;
+
+        let file = file.into_std().await;
+        let hashed = file.try_clone()?;
+        let hashing =
+            tokio::task::spawn_blocking(move || hash_file(hashed, 0, bytes, blake3::Hasher::new()));
+        let read_bytes = socket.send_file(&file, 0, bytes, progress).await?;
+        let hasher = hashing.await.map_err(std::io::Error::from)??;
+        // The puller expects `bytes` bytes so the stream cannot continue
+        check_unchanged(bytes, read_bytes)?;
+
+        write.write_all(hasher.finalize().as_bytes()).await?;
+        metadata.write_to(write).await?;
+        Ok(read_bytes)
+    }
+
+    /// Same as [`Self::send_content`] without compression but also sending what is appended to `file` meanwhile
+    ///
+    /// The metadata is collected once the file stopped growing.
+    async fn send_content_follow<W>(
+        &self,
+        mut file: File,
+        hasher: blake3::Hasher,
+        progress: &mut ProgressTracker,
+        write: &mut W,
+    ) -> Result<u64, FileTransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u8(Compression::codec(None)).await?;
+        let idle = Duration::from_secs(self.follow_idle);
+        let buffer_size = self.tuning.buffer_size();
+        let (read_bytes, hasher) =
+            send_follow(&mut file, idle, hasher, buffer_size, progress, write).await?;
+        let metadata = FileMetadata::read(&file, self.xattrs).await?;
+
+        write.write_all(hasher.finalize().as_bytes()).await?;
+        metadata.write_to(write).await?;
+        Ok(read_bytes)
+    }
+
+    /// Copy `read` to `write` followed by the digest of everything fed to `hasher` and `metadata`
+    ///
+    /// Uncompressed content is framed in chunks if its length is not announced.
+    ///
+    /// Return the number of bytes read from `read`.
+    async fn send_stream<R, W>(
+        &self,
+        read: R,
+        metadata: &FileMetadata,
+        hasher: blake3::Hasher,
+        progress: &mut ProgressTracker,
+        write: &mut W,
+    ) -> Result<u64, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u8(Compression::codec(self.compression)).await?;
+        let buffer_size = self.tuning.buffer_size();
+        let mut read = DigestRead::new(BufReader::with_capacity(buffer_size, read), hasher);
+        let mut tracked = Tracked::new(&mut read, progress);
+        match self.compression {
+            Some(compression) => {
+                let tracked = BufReader::with_capacity(buffer_size, &mut tracked);
+                compression.encode(tracked, write).await?;
+            }
+            None if self.sends_chunks() => {
+                write_chunks(&mut tracked, write).await?;
+            }
+            None => {
+                self.tuning.copy(&mut tracked, write).await?;
+            }
+        }
+        write.write_all(read.digest().as_bytes()).await?;
+        metadata.write_to(write).await?;
+        Ok(read.bytes())
     }
 }
 
-pub async fn push_file<W>(source_file: impl AsRef<Path>, mut write: W) -> io::Result<(usize, W)>
+pub async fn push_file<W>(
+    source_file: impl AsRef<Path>,
+    write: W,
+) -> Result<(usize, W), FileTransferError>
 where
     W: AsyncWrite + Unpin,
 {
-    let file = File::open(source_file).await?;
-    let bytes = file.metadata().await?.size();
-    let mut file = BufReader::new(file);
+    PushFileArgs::new(source_file.as_ref())
+        .push_file(write)
+        .await
+}
 
-    write.write_u64(bytes).await?;
-    let read_bytes = tokio::io::copy(&mut file, &mut write).await?;
+/// Push only the part of the file following the prefix the puller reports to already have
+///
+/// The whole file is sent if the puller's prefix does not match the source file.
+pub async fn push_file_resume<R, W>(
+    source_file: impl AsRef<Path>,
+    read: R,
+    write: W,
+) -> Result<(usize, R, W), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    PushFileArgs::new(source_file.as_ref())
+        .push_file_resume(read, write)
+        .await
+}
 
-    assert_eq!(bytes, read_bytes, "file modified during transmission");
+/// Push a manifest of the directory tree followed by the content of each of its files
+pub async fn push_dir<W>(
+    source_dir: impl AsRef<Path>,
+    write: W,
+) -> Result<(usize, W), FileTransferError>
+where
+    W: AsyncWrite + Unpin,
+{
+    PushFileArgs::new(source_dir.as_ref()).push_dir(write).await
+}
 
-    Ok((usize::try_from(read_bytes).unwrap(), write))
+fn check_unchanged(expected: u64, actual: u64) -> Result<(), FileTransferError> {
+    if expected != actual {
+        return Err(FileTransferError::FileChanged { expected, actual });
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct PullFileArgs {
+    /// File to pull into or `-` for stdout
     pub output_file: PathBuf,
+    /// Keep the existing output file and only receive what is missing
+    #[arg(long)]
+    pub resume: bool,
+    /// Recreate the pushed directory tree with `output_file` as its root
+    #[arg(short, long, conflicts_with = "resume")]
+    pub recursive: bool,
+    /// Receive only the parts of the file that differ from the existing output file
+    #[arg(long, conflicts_with_all = ["resume", "recursive"])]
+    pub delta: bool,
+    /// Leave the output file untouched if it is identical to the pushed file
+    ///
+    /// Modification times are only compared if they are preserved.
+    #[arg(long, conflicts_with = "recursive")]
+    pub skip_unchanged: bool,
+    /// Recreate the holes of a sparse pushed file instead of writing zeros
+    #[arg(long, conflicts_with_all = ["resume", "recursive", "delta"])]
+    pub sparse: bool,
+    /// Receive the content in chunks instead of after its length, so that the pushed file may grow meanwhile
+    #[arg(long, conflicts_with_all = ["resume", "recursive", "delta", "skip_unchanged", "sparse"])]
+    pub chunked: bool,
+    /// Keep each replaced file with a `~` suffix
+    #[arg(long)]
+    pub backup: bool,
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+    #[command(flatten)]
+    pub tuning: TuningArgs,
+    #[command(flatten)]
+    pub uring: UringArgs,
+    /// Require the peer to prove knowledge of the pre-shared key in this file
+    #[arg(long)]
+    pub psk_file: Option<PathBuf>,
+    /// Limit the transfer to this many bytes per second
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit: Option<u64>,
+    /// Bytes allowed in a burst above the rate limit; defaults to one second worth of traffic
+    #[arg(long, requires = "rate_limit", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit_burst: Option<u64>,
+    /// Show the progress of the transfer on stderr
+    #[arg(long)]
+    pub progress_bar: bool,
+    /// Receive the progress of the transfer
+    #[arg(skip)]
+    pub progress: Option<watch::Sender<Progress>>,
 }
 
 impl PullFileArgs {
-    pub async fn pull_file<R>(&self, read: R) -> io::Result<(usize, R)>
+    pub fn new(output_file: impl Into<PathBuf>) -> Self {
+        Self {
+            output_file: output_file.into(),
+            resume: false,
+            recursive: false,
+            delta: false,
+            skip_unchanged: false,
+            sparse: false,
+            chunked: false,
+            backup: false,
+            preserve: PreserveArgs::default(),
+            tuning: TuningArgs::default(),
+            uring: UringArgs::default(),
+            psk_file: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+            progress_bar: false,
+            progress: None,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::empty();
+        capabilities.set(Capabilities::RESUME, self.resume);
+        capabilities.set(Capabilities::RECURSIVE, self.recursive);
+        capabilities.set(Capabilities::DELTA, self.delta);
+        capabilities.set(Capabilities::SKIP_UNCHANGED, self.skip_unchanged);
+        capabilities.set(Capabilities::SPARSE, self.sparse);
+        capabilities.set(Capabilities::CHUNKED, self.chunked);
+        capabilities.set(Capabilities::STDOUT, self.is_stdout());
+        capabilities.set(Capabilities::XATTRS, self.preserve.xattrs);
+        capabilities.set(Capabilities::AUTH, self.psk_file.is_some());
+        capabilities
+    }
+
+    fn negotiated(&self, features: Capabilities) -> Self {
+        Self {
+            resume: features.contains(Capabilities::RESUME),
+            recursive: features.contains(Capabilities::RECURSIVE),
+            delta: features.contains(Capabilities::DELTA),
+            skip_unchanged: features.contains(Capabilities::SKIP_UNCHANGED),
+            sparse: features.contains(Capabilities::SPARSE),
+            chunked: features.contains(Capabilities::CHUNKED),
+            ..self.clone()
+        }
+    }
+
+    fn token_bucket(&self) -> Option<TokenBucket> {
+        token_bucket(self.rate_limit, self.rate_limit_burst)
+    }
+
+    pub fn is_stdout(&self) -> bool {
+        self.output_file.as_os_str() == "-"
+    }
+
+    /// Bypassing the stream for the kernel to move the content would also bypass the rate limit
+    fn file_io<'a>(&'a self, socket: Option<&'a Socket>) -> FileIo<'a> {
+        FileIo {
+            socket: socket.filter(|_| self.rate_limit.is_none()),
+            tuning: &self.tuning,
+            uring: &self.uring,
+        }
+    }
+
+    /// Report the existing output file to the pusher and learn whether it sends the content anyway
+    ///
+    /// Return `true` if the output file is identical and nothing more is to be received.
+    pub async fn skip_if_unchanged<R, W>(
+        &self,
+        read: &mut R,
+        write: &mut W,
+    ) -> Result<bool, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let fingerprint = Fingerprint::of_path(&self.output_file, self.preserve.times()).await?;
+        Fingerprint::write_to(fingerprint.as_ref(), write).await?;
+        read_verdict(read).await
+    }
+
+    pub async fn pull_file<R>(&self, read: R) -> Result<(usize, R), FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.pull_file_over(read, None).await
+    }
+
+    async fn pull_file_over<R>(
+        &self,
+        read: R,
+        socket: Option<&Socket>,
+    ) -> Result<(usize, R), FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        if self.is_stdout() {
+            return self.pull_stdout(read).await;
+        }
+        let mut read = RateLimited::new(read, self.token_bucket());
+        let part = PartFile::new(&self.output_file);
+        let mut file = part.open(true).await?;
+
+        let bytes = match self.chunked {
+            true => None,
+            false => Some(read.read_u64().await?),
+        };
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes.unwrap_or(0));
+        let (written, metadata, read) = match (self.sparse, bytes) {
+            (true, Some(bytes)) => {
+                let (written, actual) =
+                    receive_sparse(&mut read, bytes, &mut file, &mut progress).await?;
+                check_digest(&mut read, actual, Some(part.part_path())).await?;
+                let metadata = FileMetadata::read_from(&mut read).await?;
+                (written, metadata, read)
+            }
+            _ => {
+                receive_content(
+                    read,
+                    bytes,
+                    &mut file,
+                    blake3::Hasher::new(),
+                    &mut progress,
+                    part.part_path(),
+                    self.file_io(socket),
+                )
+                .await?
+            }
+        };
+        metadata.apply(&file, &self.preserve).await?;
+        part.commit(file, self.backup).await?;
+
+        Ok((to_usize(written)?, read.into_inner()))
+    }
+
+    /// Write the pushed content to stdout and discard its metadata
+    ///
+    /// The content is already written by the time its digest is checked, so a mismatch is only reported.
+    async fn pull_stdout<R>(&self, read: R) -> Result<(usize, R), FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.pull_stream(read, &mut tokio::io::stdout()).await
+    }
+
+    /// Pull the content into `sink` in place of the output file
+    ///
+    /// The digest is checked once the content is written, so `sink` may already hold corrupted content when it fails.
+    /// Metadata sent along is discarded.
+    pub async fn pull_stream<R, S>(
+        &self,
+        read: R,
+        sink: &mut S,
+    ) -> Result<(usize, R), FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        S: AsyncWrite + Unpin,
+    {
+        let mut read = RateLimited::new(read, self.token_bucket());
+        let bytes = match self.chunked {
+            true => None,
+            false => Some(read.read_u64().await?),
+        };
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes.unwrap_or(0));
+        let compression = Compression::from_codec(read.read_u8().await?)?;
+        let (written, actual, mut read) = receive_stream(
+            read,
+            compression,
+            bytes,
+            sink,
+            blake3::Hasher::new(),
+            &mut progress,
+            &self.tuning,
+        )
+        .await?;
+        sink.flush().await?;
+        check_digest(&mut read, actual, None).await?;
+        FileMetadata::read_from(&mut read).await?;
+
+        Ok((to_usize(written)?, read.into_inner()))
+    }
+
+    /// Pull the rest of a partially received file
+    ///
+    /// The content left by a failed pull is kept only if the pusher confirms it as a prefix of the source file.
+    pub async fn pull_file_resume<R, W>(
+        &self,
+        read: R,
+        mut write: W,
+    ) -> Result<(usize, R, W), FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        let mut read = RateLimited::new(read, self.token_bucket());
+        let part = PartFile::new(&self.output_file);
+        let mut file = part.open(false).await?;
+
+        let (request, mut hasher) = ResumeRequest::from_partial_file(&mut file).await?;
+        request.write_to(&mut write).await?;
+
+        let bytes = read.read_u64().await?;
+        let offset = read.read_u64().await?;
+        if (offset != 0 && offset != request.offset) || bytes < offset {
+            return Err(FileTransferError::protocol_violation(format!(
+                "invalid resume offset: {offset} of {bytes} bytes"
+            )));
+        }
+        if offset == 0 {
+            hasher = blake3::Hasher::new();
+        }
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes - offset);
+        let (written, metadata, read) = receive_content(
+            read,
+            Some(bytes - offset),
+            &mut file,
+            hasher,
+            &mut progress,
+            part.part_path(),
+            self.file_io(None),
+        )
+        .await?;
+        metadata.apply(&file, &self.preserve).await?;
+        part.commit(file, self.backup).await?;
+
+        Ok((to_usize(written)?, read.into_inner(), write))
+    }
+
+    /// Pull a file by reusing the blocks of the existing output file that the pusher refers to
+    pub async fn pull_file_delta<R, W>(
+        &self,
+        read: R,
+        mut write: W,
+    ) -> Result<(usize, R, W), FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut read = RateLimited::new(read, self.token_bucket());
+        let basis = match File::open(&self.output_file).await {
+            Ok(basis) => Some(basis),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let signature = match &basis {
+            Some(basis) => Signature::compute(basis).await?,
+            None => Signature::empty(),
+        };
+        signature.write_to(&mut write).await?;
+
+        let bytes = read.read_u64().await?;
+        let part = PartFile::new(&self.output_file);
+        let mut file = part.open(true).await?;
+        let mut progress = ProgressTracker::new(self.progress.clone(), bytes);
+        let (written, actual) = receive_delta(
+            &mut read,
+            &signature,
+            basis,
+            bytes,
+            &mut file,
+            &mut progress,
+        )
+        .await?;
+        if written != bytes {
+            return Err(FileTransferError::protocol_violation(
+                "delta shorter than announced",
+            ));
+        }
+        check_digest(&mut read, actual, Some(part.part_path())).await?;
+        let metadata = FileMetadata::read_from(&mut read).await?;
+        metadata.apply(&file, &self.preserve).await?;
+        part.commit(file, self.backup).await?;
+
+        Ok((to_usize(written)?, read.into_inner(), write))
+    }
+
+    /// Pull a directory tree into `output_file`
+    ///
+    /// Existing files in the tree are replaced.
+    pub async fn pull_dir<R>(&self, read: R) -> Result<(usize, R), FileTransferError>
     where
         R: AsyncRead + Unpin + Send + 'static,
     {
-        pull_file(&self.output_file, read).await
+        let mut read = RateLimited::new(read, self.token_bucket());
+        let output_dir = Sandbox::new(&self.output_file);
+        tokio::fs::create_dir_all(&self.output_file).await?;
+        let manifest = Manifest::read_from(&mut read).await?;
+
+        let mut progress = ProgressTracker::new(self.progress.clone(), manifest.total_size());
+        let mut total_bytes = 0;
+        for entry in &manifest.entries {
+            let path = output_dir.resolve(&entry.path).await?;
+            match entry.kind {
+                EntryKind::Dir => {
+                    tokio::fs::create_dir_all(&path).await?;
+                }
+                EntryKind::File => {
+                    let part = PartFile::new(&path);
+                    let mut file = part.open(true).await?;
+                    let (written, metadata);
+                    (written, metadata, read) = receive_content(
+                        read,
+                        Some(entry.size),
+                        &mut file,
+                        blake3::Hasher::new(),
+                        &mut progress,
+                        part.part_path(),
+                        self.file_io(None),
+                    )
+                    .await?;
+                    dir::set_mode(part.part_path(), entry.mode, &self.preserve).await?;
+                    metadata.apply(&file, &self.preserve).await?;
+                    part.commit(file, self.backup).await?;
+                    total_bytes += written;
+                }
+            }
+        }
+        // Directories might become read-only so apply their modes after their content is written
+        for entry in manifest.entries.iter().rev() {
+            if entry.kind == EntryKind::Dir {
+                let path = output_dir.resolve(&entry.path).await?;
+                dir::set_mode(&path, entry.mode, &self.preserve).await?;
+            }
+        }
+
+        Ok((to_usize(total_bytes)?, read.into_inner()))
     }
 }
 
-pub async fn pull_file<R>(output_file: impl AsRef<Path>, mut read: R) -> io::Result<(usize, R)>
+pub async fn pull_file<R>(
+    output_file: impl AsRef<Path>,
+    read: R,
+) -> Result<(usize, R), FileTransferError>
 where
     R: AsyncRead + Unpin + Send + 'static,
 {
-    let _ = tokio::fs::remove_file(&output_file).await;
-    let mut file = File::options()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(output_file)
-        .await?;
+    PullFileArgs::new(output_file.as_ref())
+        .pull_file(read)
+        .await
+}
+
+/// Pull the rest of a partially received file
+///
+/// The content left by a failed pull is kept only if the pusher confirms it as a prefix of the source file.
+pub async fn pull_file_resume<R, W>(
+    output_file: impl AsRef<Path>,
+    read: R,
+    write: W,
+) -> Result<(usize, R, W), FileTransferError>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    PullFileArgs::new(output_file.as_ref())
+        .pull_file_resume(read, write)
+        .await
+}
+
+/// Pull a directory tree into `output_dir`
+///
+/// Existing files in the tree are replaced.
+pub async fn pull_dir<R>(
+    output_dir: impl AsRef<Path>,
+    read: R,
+) -> Result<(usize, R), FileTransferError>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    PullFileArgs::new(output_dir.as_ref()).pull_dir(read).await
+}
+
+/// Copy `bytes` bytes from `read` to `file`, verify them against the trailing digest and return the metadata following it
+///
+/// Content of unknown length, `bytes` being `None`, is read in chunks up to the end marker.
+///
+/// `hasher` is expected to have been fed with the part of the file before the cursor.
+///
+/// `path` is removed if the digest does not match.
+///
+/// Content is written to `file` as chosen by `file_io`.
+async fn receive_content<R>(
+    mut read: R,
+    bytes: Option<u64>,
+    file: &mut File,
+    hasher: blake3::Hasher,
+    progress: &mut ProgressTracker,
+    path: &Path,
+    file_io: FileIo<'_>,
+) -> Result<(u64, FileMetadata, R), FileTransferError>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let compression = Compression::from_codec(read.read_u8().await?)?;
+    let tuning = file_io.tuning;
+    let (written, actual, mut read) = match (compression, bytes, file_io.socket) {
+        (None, Some(bytes), Some(socket)) => {
+            let start = file.stream_position().await?;
+            let std_file = file.try_clone().await?.into_std().await;
+            socket
+                .receive_file(&std_file, start, bytes, progress)
+                .await?;
+            let hasher =
+                tokio::task::spawn_blocking(move || hash_file(std_file, start, bytes, hasher))
+                    .await
+                    .map_err(std::io::Error::from)??;
+            file.seek(SeekFrom::Start(start + bytes)).await?;
+            (bytes, hasher.finalize(), read)
+        }
+        (None, _, _) => {
+            let start = file.stream_position().await?;
+            match file_io.uring.writer(file, start).await? {
+                Some(mut writer) => {
+                    let (written, actual, read) =
+                        receive_stream(read, None, bytes, &mut writer, hasher, progress, tuning)
+                            .await?;
+                    writer.shutdown().await?;
+                    file.seek(SeekFrom::Start(start + written)).await?;
+                    (written, actual, read)
+                }
+                None => receive_stream(read, None, bytes, file, hasher, progress, tuning).await?,
+            }
+        }
+        (Some(_), _, _) => {
+            receive_stream(read, compression, bytes, file, hasher, progress, tuning).await?
+        }
+    };
 
-    let bytes = read.read_u64().await?;
-    let read_exact = ReadExact::new(read, usize::try_from(bytes).unwrap());
-    let mut read = read_exact.into_async_read();
-    let written = tokio::io::copy(&mut read, &mut file).await?;
+    check_digest(&mut read, actual, Some(path)).await?;
+    let metadata = FileMetadata::read_from(&mut read).await?;
 
-    Ok((
-        usize::try_from(written).unwrap(),
-        read.into_inner().into_inner(),
-    ))
+    Ok((written, metadata, read))
+}
+
+/// Decode the content following the codec byte from `read` and copy it to `write`
+///
+/// Content of unknown length, `bytes` being `None`, is read in chunks up to the end marker.
+///
+/// Return the number of bytes written and the digest of everything fed to `hasher`.
+async fn receive_stream<R, W>(
+    read: R,
+    compression: Option<Compression>,
+    bytes: Option<u64>,
+    write: &mut W,
+    hasher: blake3::Hasher,
+    progress: &mut ProgressTracker,
+    tuning: &TuningArgs,
+) -> Result<(u64, blake3::Hash, R), FileTransferError>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    match (compression, bytes) {
+        (Some(compression), _) => {
+            let chunks = ChunkedRead::new(read).into_async_read();
+            let mut chunks = BufReader::with_capacity(tuning.buffer_size(), chunks);
+            let mut decoder = DigestRead::new(compression.decoder(&mut chunks), hasher);
+            let limit = bytes.unwrap_or(u64::MAX);
+            let mut tracked = Tracked::new((&mut decoder).take(limit), progress);
+            let written = tuning.copy(&mut tracked, write).await?;
+            if let Some(bytes) = bytes {
+                if written != bytes {
+                    return Err(FileTransferError::protocol_violation(
+                        "decompressed content shorter than announced",
+                    ));
+                }
+                if decoder.read(&mut [0]).await? != 0 {
+                    return Err(FileTransferError::protocol_violation(
+                        "decompressed content longer than announced",
+                    ));
+                }
+            }
+            let actual = decoder.digest();
+            drop(decoder);
+            if chunks.read(&mut [0]).await? != 0 {
+                return Err(FileTransferError::protocol_violation(
+                    "trailing data after compressed content",
+                ));
+            }
+            let read = chunks.into_inner().into_inner().into_inner();
+            Ok((written, actual, read))
+        }
+        (None, Some(bytes)) => {
+            let read_exact = ReadExact::new(read, to_usize(bytes)?);
+            let mut read = DigestRead::new(read_exact.into_async_read(), hasher);
+            let written = tuning
+                .copy(&mut Tracked::new(&mut read, progress), write)
+                .await?;
+            let actual = read.digest();
+            Ok((written, actual, read.into_inner().into_inner().into_inner()))
+        }
+        (None, None) => {
+            let chunks = ChunkedRead::new(read).into_async_read();
+            let mut read = DigestRead::new(chunks, hasher);
+            let written = tuning
+                .copy(&mut Tracked::new(&mut read, progress), write)
+                .await?;
+            let actual = read.digest();
+            Ok((written, actual, read.into_inner().into_inner().into_inner()))
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FileIo<'a> {
+    /// The socket underlying the stream if the kernel is to move the content
+    socket: Option<&'a Socket>,
+    tuning: &'a TuningArgs,
+    uring: &'a UringArgs,
+}
+
+/// Read the digest sent by the pusher and fail if it is not `actual`
+///
+/// `path`, if any, is removed if the digest does not match.
+async fn check_digest<R>(
+    read: &mut R,
+    actual: blake3::Hash,
+    path: Option<&Path>,
+) -> Result<(), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut expected = [0; blake3::OUT_LEN];
+    read.read_exact(&mut expected).await?;
+    let expected = blake3::Hash::from_bytes(expected);
+    if expected != actual {
+        if let Some(path) = path {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        return Err(DigestMismatchError { expected, actual }.into());
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct FileTransferStats {
+    /// File content transferred
     pub bytes: usize,
+    /// Everything sent and received over the streams
+    pub wire_bytes: usize,
     pub throughput_mib_s: f64,
+    /// Measured rate of [`Self::wire_bytes`], which is what [`Self::rate_limit`] throttles
+    pub wire_throughput_mib_s: f64,
     pub latency_ms: f64,
+    /// Bytes per second this side was throttled to
+    pub rate_limit: Option<u64>,
+    /// The puller already had an identical file so no content was transferred
+    pub skipped: bool,
 }
 impl core::fmt::Display for FileTransferStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "bytes: {bytes}; throughput: {throughput_mib_s:.2} MiB/s; latency: {latency_ms:.2} ms;",
+            "bytes: {bytes}; wire bytes: {wire_bytes}; throughput: {throughput_mib_s:.2} MiB/s; latency: {latency_ms:.2} ms;",
             bytes = self.bytes,
+            wire_bytes = self.wire_bytes,
             throughput_mib_s = self.throughput_mib_s,
             latency_ms = self.latency_ms,
-        )
+        )?;
+        if let Some(rate_limit) = self.rate_limit {
+            let rate_limit_mib_s = rate_limit as f64 / 1024. / 1024.;
+            write!(
+                f,
+                " rate limit: {rate_limit_mib_s:.2} MiB/s; wire throughput: {wire_throughput_mib_s:.2} MiB/s;",
+                wire_throughput_mib_s = self.wire_throughput_mib_s,
+            )?;
+        }
+        if self.skipped {
+            write!(f, " skipped;")?;
+        }
+        Ok(())
     }
 }
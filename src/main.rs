@@ -0,0 +1,157 @@
+use std::{net::IpAddr, path::PathBuf, process::ExitCode};
+
+use clap::{Args, Parser, Subcommand};
+use file_transfer::{
+    connect, connect_parallel, daemon, serve, serve_parallel, ClientTlsArgs, DaemonArgs,
+    DaemonEvent, FileTransferCommand, FileTransferError, FileTransferStats, ServerTlsArgs,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Transfer files over TCP, optionally secured with TLS"
+)]
+struct Cli {
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+    /// Wait for one peer to connect and run the transfer with it
+    Serve(ServeArgs),
+    /// Connect to a serving peer and run the transfer with it
+    Connect(ConnectArgs),
+    /// Keep running the transfer with every peer that connects until terminated
+    ///
+    /// Each peer names the path to transfer under the source or output path with `--remote-path`.
+    Daemon(DaemonModeArgs),
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    listen: IpAddr,
+    /// Port to listen on
+    #[arg(long)]
+    port: u16,
+    #[command(flatten)]
+    tls: ServerTlsArgs,
+    /// Split the file into this many ranges transferred over as many connections at once
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=256))]
+    streams: u32,
+    #[command(subcommand)]
+    transfer: FileTransferCommand,
+}
+
+#[derive(Debug, Args)]
+struct ConnectArgs {
+    /// Host name or address of the serving peer
+    host: String,
+    /// Port the serving peer listens on
+    #[arg(long)]
+    port: u16,
+    #[command(flatten)]
+    tls: ClientTlsArgs,
+    /// Path under the root of the daemon to push to or pull from
+    #[arg(long, conflicts_with = "streams")]
+    remote_path: Option<PathBuf>,
+    /// Split the file into this many ranges transferred over as many connections at once
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=256))]
+    streams: u32,
+    #[command(subcommand)]
+    transfer: FileTransferCommand,
+}
+
+#[derive(Debug, Args)]
+struct DaemonModeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    listen: IpAddr,
+    /// Port to listen on
+    #[arg(long)]
+    port: u16,
+    #[command(flatten)]
+    daemon: DaemonArgs,
+    #[command(flatten)]
+    tls: ServerTlsArgs,
+    #[command(subcommand)]
+    transfer: FileTransferCommand,
+}
+
+/// Print the stats of a finished transfer where they do not mix with its content
+fn report(transfer: &FileTransferCommand, stats: FileTransferStats) {
+    match transfer.writes_stdout() {
+        true => eprintln!("{stats}"),
+        false => println!("{stats}"),
+    }
+}
+
+/// Run the daemon until SIGTERM or SIGINT and print how every peer fared
+async fn run_daemon(args: &DaemonModeArgs) -> Result<(), FileTransferError> {
+    let listener = TcpListener::bind((args.listen, args.port)).await?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = sigint.recv() => (),
+        }
+    };
+    let report = |event| match event {
+        DaemonEvent::AcceptFailed(e) => eprintln!("failed to accept: {e}"),
+        DaemonEvent::Served {
+            peer,
+            result: Ok(stats),
+        } => eprintln!("{peer}: {stats}"),
+        DaemonEvent::Served {
+            peer,
+            result: Err(e),
+        } => eprintln!("{peer}: error: {e}"),
+    };
+    let (daemon_args, tls, transfer) = (&args.daemon, &args.tls, &args.transfer);
+    daemon(listener, daemon_args, tls, transfer, shutdown, report).await
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.mode {
+        Mode::Serve(args) => {
+            let listen = (args.listen, args.port).into();
+            let stats = match args.streams {
+                1 => serve(listen, &args.tls, &args.transfer).await,
+                streams => {
+                    serve_parallel(listen, &args.tls, streams as usize, &args.transfer).await
+                }
+            };
+            stats.map(|stats| report(&args.transfer, stats))
+        }
+        Mode::Connect(args) => {
+            let (host, port, tls) = (&args.host, args.port, &args.tls);
+            let stats = match args.streams {
+                1 => {
+                    let remote_path = args.remote_path.as_deref();
+                    connect(host, port, tls, remote_path, &args.transfer).await
+                }
+                streams => {
+                    connect_parallel(host, port, tls, streams as usize, &args.transfer).await
+                }
+            };
+            stats.map(|stats| report(&args.transfer, stats))
+        }
+        Mode::Daemon(args) => run_daemon(args).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
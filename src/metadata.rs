@@ -0,0 +1,282 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{FileTimes, Permissions},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    time::{Duration, SystemTime},
+};
+
+use clap::Args;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use xattr::FileExt;
+
+use crate::FileTransferError;
+
+const MAX_XATTRS: u32 = 1024;
+const MAX_XATTR_NAME_LEN: u16 = 255;
+const MAX_XATTR_VALUE_LEN: u32 = 1024 * 64;
+
+/// Which metadata of the pushed files to apply to the pulled ones
+#[derive(Debug, Clone, Default, Args)]
+pub struct PreserveArgs {
+    /// Same as `-ptog`
+    #[arg(short, long)]
+    pub archive: bool,
+    /// Preserve permission bits
+    #[arg(short, long)]
+    pub perms: bool,
+    /// Preserve access and modification times
+    #[arg(short, long)]
+    pub times: bool,
+    /// Preserve the owner; usually requires root
+    #[arg(short, long)]
+    pub owner: bool,
+    /// Preserve the group
+    #[arg(short, long)]
+    pub group: bool,
+    /// Preserve extended attributes
+    #[arg(short = 'X', long)]
+    pub xattrs: bool,
+}
+impl PreserveArgs {
+    fn perms(&self) -> bool {
+        self.archive || self.perms
+    }
+
+    pub fn times(&self) -> bool {
+        self.archive || self.times
+    }
+
+    fn owner(&self) -> bool {
+        self.archive || self.owner
+    }
+
+    fn group(&self) -> bool {
+        self.archive || self.group
+    }
+
+    /// The bits of a pushed `mode` to apply to the pulled file
+    ///
+    /// The set-user-ID, set-group-ID and sticky bits are only kept if both the permissions and the owner are preserved.
+    pub(crate) fn permission_bits(&self, mode: u32) -> u32 {
+        match self.perms() && self.owner() {
+            true => mode & 0o7777,
+            false => mode & 0o777,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+impl Timestamp {
+    fn to_system_time(self) -> io::Result<SystemTime> {
+        let secs = Duration::from_secs(self.secs.unsigned_abs());
+        let whole_secs = match self.secs < 0 {
+            true => SystemTime::UNIX_EPOCH.checked_sub(secs),
+            false => SystemTime::UNIX_EPOCH.checked_add(secs),
+        };
+        whole_secs
+            .and_then(|time| time.checked_add(Duration::from_nanos(self.nanos.into())))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "timestamp out of range"))
+    }
+}
+
+/// Metadata sent after the content of each file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    accessed: Timestamp,
+    modified: Timestamp,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+}
+impl FileMetadata {
+    /// Collect the metadata of `file` and optionally its extended attributes
+    pub async fn read(file: &File, xattrs: bool) -> io::Result<Self> {
+        let metadata = file.metadata().await?;
+        let xattrs = match xattrs {
+            true => {
+                let file = file.try_clone().await?.into_std().await;
+                tokio::task::spawn_blocking(move || read_xattrs(&file)).await??
+            }
+            false => vec![],
+        };
+        Ok(Self {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            accessed: Timestamp {
+                secs: metadata.atime(),
+                nanos: metadata.atime_nsec() as u32,
+            },
+            modified: Timestamp {
+                secs: metadata.mtime(),
+                nanos: metadata.mtime_nsec() as u32,
+            },
+            xattrs,
+        })
+    }
+
+    /// Metadata for content that does not come from a file
+    ///
+    /// The content is owned by the current user, readable by everyone and modified now.
+    pub fn for_stream() -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let now = Timestamp {
+            secs: now.as_secs() as i64,
+            nanos: now.subsec_nanos(),
+        };
+        Self {
+            // A regular file; `S_IFREG` is not a `u32` everywhere
+            mode: 0o100644,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            accessed: now,
+            modified: now,
+            xattrs: vec![],
+        }
+    }
+
+    pub async fn write_to<W>(&self, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u32(self.mode).await?;
+        write.write_u32(self.uid).await?;
+        write.write_u32(self.gid).await?;
+        for timestamp in [self.accessed, self.modified] {
+            write.write_i64(timestamp.secs).await?;
+            write.write_u32(timestamp.nanos).await?;
+        }
+        write.write_u32(self.xattrs.len() as u32).await?;
+        for (name, value) in &self.xattrs {
+            let name = name.as_bytes();
+            write.write_u16(name.len() as u16).await?;
+            write.write_all(name).await?;
+            write.write_u32(value.len() as u32).await?;
+            write.write_all(value).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn read_from<R>(read: &mut R) -> Result<Self, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mode = read.read_u32().await?;
+        let uid = read.read_u32().await?;
+        let gid = read.read_u32().await?;
+        let mut timestamps = [Timestamp { secs: 0, nanos: 0 }; 2];
+        for timestamp in &mut timestamps {
+            let secs = read.read_i64().await?;
+            let nanos = read.read_u32().await?;
+            if 1_000_000_000 <= nanos {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "invalid timestamp nanoseconds: {nanos}"
+                )));
+            }
+            *timestamp = Timestamp { secs, nanos };
+        }
+        let [accessed, modified] = timestamps;
+
+        let count = read.read_u32().await?;
+        if MAX_XATTRS < count {
+            return Err(FileTransferError::protocol_violation(format!(
+                "too many extended attributes: {count}"
+            )));
+        }
+        let mut xattrs = vec![];
+        for _ in 0..count {
+            let name_len = read.read_u16().await?;
+            if MAX_XATTR_NAME_LEN < name_len {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "extended attribute name too long: {name_len} bytes"
+                )));
+            }
+            let mut name = vec![0; usize::from(name_len)];
+            read.read_exact(&mut name).await?;
+            let value_len = read.read_u32().await?;
+            if MAX_XATTR_VALUE_LEN < value_len {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "extended attribute value too long: {value_len} bytes"
+                )));
+            }
+            let mut value = vec![0; value_len as usize];
+            read.read_exact(&mut value).await?;
+            xattrs.push((OsStr::from_bytes(&name).to_owned(), value));
+        }
+
+        Ok(Self {
+            mode,
+            uid,
+            gid,
+            accessed,
+            modified,
+            xattrs,
+        })
+    }
+
+    /// Apply the metadata selected by `preserve` to `file`
+    pub async fn apply(&self, file: &File, preserve: &PreserveArgs) -> io::Result<()> {
+        let file = file.try_clone().await?.into_std().await;
+        let this = self.clone();
+        let preserve = preserve.clone();
+        tokio::task::spawn_blocking(move || {
+            // Changing the owner might clear the set-user-ID and set-group-ID bits so it goes first
+            let uid = preserve.owner().then_some(this.uid);
+            let gid = preserve.group().then_some(this.gid);
+            if uid.is_some() || gid.is_some() {
+                std::os::unix::fs::fchown(&file, uid, gid)?;
+            }
+            if preserve.xattrs {
+                for (name, value) in &this.xattrs {
+                    file.set_xattr(name, value)?;
+                }
+            }
+            if preserve.perms() {
+                file.set_permissions(Permissions::from_mode(preserve.permission_bits(this.mode)))?;
+            }
+            if preserve.times() {
+                let times = FileTimes::new()
+                    .set_accessed(this.accessed.to_system_time()?)
+                    .set_modified(this.modified.to_system_time()?);
+                file.set_times(times)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Attributes beyond the protocol limits are left out
+fn read_xattrs(file: &std::fs::File) -> io::Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = vec![];
+    for name in file.list_xattr()? {
+        if usize::from(MAX_XATTR_NAME_LEN) < name.len() {
+            continue;
+        }
+        let Some(value) = file.get_xattr(&name)? else {
+            continue;
+        };
+        if (MAX_XATTR_VALUE_LEN as usize) < value.len() {
+            continue;
+        }
+        xattrs.push((name, value));
+        if xattrs.len() == MAX_XATTRS as usize {
+            break;
+        }
+    }
+    Ok(xattrs)
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashSet, io, io::SeekFrom, os::unix::fs::MetadataExt, sync::Arc, time::Instant,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    task::JoinSet,
+};
+
+use crate::{
+    atomic::PartFile, check_unchanged, counter::Counted, error::to_usize, handshake::Capabilities,
+    metadata::FileMetadata, progress::ProgressTracker, rate_limit::token_bucket,
+    rate_limit::RateLimited, receive_content, FileTransferCommand, FileTransferError,
+    FileTransferResult, FileTransferStats, PullFileArgs, PushFileArgs, CLOSE,
+};
+
+/// What one stream reports back once its range is transferred
+struct RangeOutcome<R, W> {
+    /// Position of the stream in the list passed in
+    stream: usize,
+    /// Size of the whole file as announced on this stream
+    file_bytes: u64,
+    /// Which of the ranges the stream carried
+    range: u32,
+    bytes: u64,
+    metadata: Option<FileMetadata>,
+    read: Counted<R>,
+    write: Counted<W>,
+}
+
+impl FileTransferCommand {
+    /// Transfer a single file split into as many ranges as `streams`, each over its own stream at once
+    ///
+    /// Both peers must pass the same number of streams.
+    /// The puller writes each range at its offset in the output file.
+    ///
+    /// Progress is not reported and a rate limit is shared evenly among the streams.
+    pub async fn perform_parallel<R, W>(
+        &self,
+        streams: Vec<(R, W)>,
+    ) -> Result<FileTransferResult<Vec<R>, Vec<W>>, FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let start = Instant::now();
+        let count = u32::try_from(streams.len())
+            .ok()
+            .filter(|count| 0 < *count)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported number of streams: {}", streams.len()),
+                )
+            })?;
+        let psk: Option<Arc<[u8]>> = self.psk().await?.map(Arc::from);
+        // The locked handle is held until the ranges are all received and committed through it
+        let (part, mut locked) = match self {
+            FileTransferCommand::Push(_) => (None, None),
+            // Left for the handshake to refuse so that the pusher learns of it as well
+            FileTransferCommand::Pull(args) if args.is_stdout() => (None, None),
+            FileTransferCommand::Pull(args) => {
+                let part = PartFile::new(&args.output_file);
+                let locked = part.open(true).await?;
+                (Some(part), Some(locked))
+            }
+        };
+
+        let mut tasks = JoinSet::new();
+        for (stream, (read, write)) in streams.into_iter().enumerate() {
+            let command = self.clone();
+            let psk = psk.clone();
+            let part = part.clone();
+            tasks.spawn(async move {
+                let mut read = Counted::new(read);
+                let mut write = Counted::new(write);
+                let requested = command.capabilities() | Capabilities::RANGES;
+                let features = command
+                    .establish(&mut read, &mut write, psk.as_deref(), requested)
+                    .await?;
+                let outcome = match command {
+                    FileTransferCommand::Push(args) => {
+                        let args = args.negotiated(features);
+                        args.push_range(stream, count, read, write).await?
+                    }
+                    FileTransferCommand::Pull(args) => {
+                        let args = args.negotiated(features);
+                        let part = part.expect("pulls have a part file");
+                        args.pull_range(stream, count, &part, read, write).await?
+                    }
+                };
+                Ok::<_, FileTransferError>(outcome)
+            });
+        }
+
+        let mut outcomes = vec![];
+        while let Some(outcome) = tasks.join_next().await {
+            outcomes.push(outcome.map_err(io::Error::from)??);
+        }
+        outcomes.sort_by_key(|outcome| outcome.stream);
+
+        if let (FileTransferCommand::Pull(args), Some(part)) = (self, part) {
+            let file_bytes = outcomes[0].file_bytes;
+            let ranges: HashSet<u32> = outcomes.iter().map(|outcome| outcome.range).collect();
+            if outcomes
+                .iter()
+                .any(|outcome| outcome.file_bytes != file_bytes)
+            {
+                return Err(FileTransferError::protocol_violation(
+                    "streams announced different file sizes",
+                ));
+            }
+            if ranges.len() != outcomes.len() {
+                return Err(FileTransferError::protocol_violation(
+                    "streams carried the same range",
+                ));
+            }
+            let file = locked.take().expect("pulls lock their part file");
+            file.set_len(file_bytes).await?;
+            if let Some(metadata) = &outcomes[0].metadata {
+                metadata.apply(&file, &args.preserve).await?;
+            }
+            part.commit(file, args.backup).await?;
+        }
+
+        let duration = start.elapsed();
+        let mut bytes = 0;
+        let mut wire_bytes = 0;
+        let mut reads = vec![];
+        let mut writes = vec![];
+        for outcome in outcomes {
+            bytes += outcome.bytes;
+            wire_bytes += outcome.read.bytes() + outcome.write.bytes();
+            reads.push(outcome.read.into_inner());
+            writes.push(outcome.write.into_inner());
+        }
+        let throughput = bytes as f64 / duration.as_secs_f64();
+        let stats = FileTransferStats {
+            bytes: to_usize(bytes)?,
+            wire_bytes: to_usize(wire_bytes)?,
+            throughput_mib_s: throughput / 1024. / 1024.,
+            wire_throughput_mib_s: wire_bytes as f64 / duration.as_secs_f64() / 1024. / 1024.,
+            latency_ms: duration.as_secs_f64() * 1000.,
+            rate_limit: self.rate_limit(),
+            skipped: false,
+        };
+        Ok(FileTransferResult {
+            stats,
+            read: reads,
+            write: writes,
+        })
+    }
+}
+
+impl PushFileArgs {
+    /// Push the range of the file assigned to the `stream`th of `count` streams
+    async fn push_range<R, W>(
+        &self,
+        stream: usize,
+        count: u32,
+        mut read: Counted<R>,
+        mut write: Counted<W>,
+    ) -> Result<RangeOutcome<R, W>, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let range = stream as u32;
+        let bucket = token_bucket(
+            self.rate_limit.map(|rate| rate / u64::from(count)),
+            self.rate_limit_burst.map(|burst| burst / u64::from(count)),
+        );
+        let mut limited = RateLimited::new(&mut write, bucket);
+        let mut file = File::open(&self.source_file).await?;
+        let file_bytes = file.metadata().await?.size();
+        let (offset, bytes) = range_of(file_bytes, count, range);
+
+        limited.write_u64(file_bytes).await?;
+        limited.write_u32(count).await?;
+        limited.write_u32(range).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let metadata = FileMetadata::read(&file, self.xattrs).await?;
+        let mut progress = ProgressTracker::new(None, bytes);
+        let read_bytes = self
+            .send_stream(
+                file.take(bytes),
+                &metadata,
+                blake3::Hasher::new(),
+                &mut progress,
+                &mut limited,
+            )
+            .await?;
+        limited.flush().await?;
+
+        check_unchanged(bytes, read_bytes)?;
+
+        let msg = read.read_u8().await?;
+        if msg != CLOSE {
+            return Err(FileTransferError::protocol_violation(format!(
+                "expected close message; got {msg}"
+            )));
+        }
+        Ok(RangeOutcome {
+            stream,
+            file_bytes,
+            range,
+            bytes,
+            metadata: None,
+            read,
+            write,
+        })
+    }
+}
+
+impl PullFileArgs {
+    /// Pull whichever range the pusher sends over this stream into its place in `part`
+    async fn pull_range<R, W>(
+        &self,
+        stream: usize,
+        count: u32,
+        part: &PartFile,
+        read: Counted<R>,
+        mut write: Counted<W>,
+    ) -> Result<RangeOutcome<R, W>, FileTransferError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        let bucket = token_bucket(
+            self.rate_limit.map(|rate| rate / u64::from(count)),
+            self.rate_limit_burst.map(|burst| burst / u64::from(count)),
+        );
+        let mut read = RateLimited::new(read, bucket);
+        let file_bytes = read.read_u64().await?;
+        let peer_count = read.read_u32().await?;
+        let range = read.read_u32().await?;
+        if peer_count != count {
+            return Err(FileTransferError::incompatible(format!(
+                "peer splits the file into {peer_count} ranges; expected {count}"
+            )));
+        }
+        if count <= range {
+            return Err(FileTransferError::protocol_violation(format!(
+                "invalid range: {range}"
+            )));
+        }
+        let (offset, bytes) = range_of(file_bytes, count, range);
+
+        // Each stream writes through its own handle so that their offsets do not interfere
+        let mut file = part.reopen().await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut progress = ProgressTracker::new(None, bytes);
+        let (written, metadata, read) = receive_content(
+            read,
+            Some(bytes),
+            &mut file,
+            blake3::Hasher::new(),
+            &mut progress,
+            part.part_path(),
+            self.file_io(None),
+        )
+        .await?;
+        file.flush().await?;
+
+        write.write_u8(CLOSE).await?;
+        Ok(RangeOutcome {
+            stream,
+            file_bytes,
+            range,
+            bytes: written,
+            metadata: Some(metadata),
+            read: read.into_inner(),
+            write,
+        })
+    }
+}
+
+/// Offset and length of the `range`th of `count` nearly equal ranges of `bytes` bytes
+fn range_of(bytes: u64, count: u32, range: u32) -> (u64, u64) {
+    let boundary = |range: u32| (u128::from(bytes) * u128::from(range) / u128::from(count)) as u64;
+    let offset = boundary(range);
+    (offset, boundary(range + 1) - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    type Streams = Vec<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)>;
+
+    /// `count` connected streams, one end of each for either peer
+    fn streams(count: usize) -> (Streams, Streams) {
+        (0..count)
+            .map(|_| {
+                let (push, pull) = tokio::io::duplex(1024 * 64);
+                (tokio::io::split(push), tokio::io::split(pull))
+            })
+            .unzip()
+    }
+
+    fn source(dir: &Path, len: usize) -> (PathBuf, Vec<u8>) {
+        let path = dir.join("source");
+        let content: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        (path, content)
+    }
+
+    /// Establish a stream the way a pusher of ranges does
+    async fn establish(
+        args: &PushFileArgs,
+        (read, write): (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>),
+    ) -> (
+        Counted<ReadHalf<DuplexStream>>,
+        Counted<WriteHalf<DuplexStream>>,
+    ) {
+        let mut read = Counted::new(read);
+        let mut write = Counted::new(write);
+        FileTransferCommand::Push(args.clone())
+            .establish(&mut read, &mut write, None, Capabilities::RANGES)
+            .await
+            .unwrap();
+        (read, write)
+    }
+
+    async fn round_trip(len: usize, count: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, content) = source(dir.path(), len);
+        let output = dir.path().join("output");
+        let push = FileTransferCommand::Push(PushFileArgs::new(&source));
+        let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+        let (push_streams, pull_streams) = streams(count);
+        let (pushed, pulled) = tokio::join!(
+            push.perform_parallel(push_streams),
+            pull.perform_parallel(pull_streams),
+        );
+        assert_eq!(pushed.unwrap().stats.bytes, len);
+        assert_eq!(pulled.unwrap().stats.bytes, len);
+        assert!(std::fs::read(&output).unwrap() == content);
+    }
+
+    #[test]
+    fn ranges_cover_the_file() {
+        for bytes in [0, 1, 7, 1_000_003] {
+            for count in [1, 2, 3, 7, 256] {
+                let mut end = 0;
+                for range in 0..count {
+                    let (offset, len) = range_of(bytes, count, range);
+                    assert_eq!(offset, end, "{bytes} bytes in {count} ranges");
+                    assert!(len <= bytes / u64::from(count) + 1);
+                    end = offset + len;
+                }
+                assert_eq!(end, bytes, "{bytes} bytes in {count} ranges");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_round_trip() {
+        round_trip(1024 * 1024, 4).await;
+    }
+
+    #[tokio::test]
+    async fn uneven_ranges_round_trip() {
+        round_trip(1_000_003, 3).await;
+        round_trip(2, 3).await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_ranges_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, _) = source(dir.path(), 100_000);
+        let output = dir.path().join("output");
+        let (push_streams, pull_streams) = streams(2);
+        for stream in push_streams {
+            let args = PushFileArgs::new(&source);
+            tokio::spawn(async move {
+                let (read, write) = establish(&args, stream).await;
+                // Every stream claims the first range
+                args.push_range(0, 2, read, write).await
+            });
+        }
+
+        let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+        let e = pull.perform_parallel(pull_streams).await.unwrap_err();
+        assert!(
+            matches!(&e, FileTransferError::ProtocolViolation(message) if message.contains("same range")),
+            "unexpected error: {e}"
+        );
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn out_of_bounds_range_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, _) = source(dir.path(), 100);
+        let output = dir.path().join("output");
+        let (mut push_streams, pull_streams) = streams(1);
+        let stream = push_streams.pop().unwrap();
+        let faking = async {
+            let (read, mut write) = establish(&PushFileArgs::new(&source), stream).await;
+            write.write_u64(100).await.unwrap();
+            write.write_u32(1).await.unwrap();
+            // The only valid range of one is 0
+            write.write_u32(1).await.unwrap();
+            write.flush().await.unwrap();
+            (read, write)
+        };
+
+        let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+        let (_stream, pulled) = tokio::join!(faking, pull.perform_parallel(pull_streams));
+        let e = pulled.unwrap_err();
+        assert!(
+            matches!(&e, FileTransferError::ProtocolViolation(message) if message.contains("invalid range")),
+            "unexpected error: {e}"
+        );
+        assert!(!output.exists());
+    }
+}
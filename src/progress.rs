@@ -0,0 +1,165 @@
+use std::{
+    io::Write,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::watch,
+    task::JoinHandle,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Snapshot of an ongoing transfer
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Progress {
+    /// File content transferred so far
+    pub bytes: u64,
+    /// File content to transfer in total
+    pub total: u64,
+    /// Bytes per second over the last sampling interval
+    pub rate: f64,
+}
+impl Progress {
+    pub fn eta(&self) -> Option<Duration> {
+        if self.rate <= 0. {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.bytes);
+        Some(Duration::from_secs_f64(remaining as f64 / self.rate))
+    }
+}
+impl core::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = match self.total {
+            0 => 100.,
+            total => self.bytes as f64 / total as f64 * 100.,
+        };
+        write!(
+            f,
+            "{bytes}/{total} bytes ({percent:.1}%); rate: {rate_mib_s:.2} MiB/s; eta: ",
+            bytes = self.bytes,
+            total = self.total,
+            rate_mib_s = self.rate / 1024. / 1024.,
+        )?;
+        match self.eta() {
+            Some(eta) => write!(f, "{} s;", eta.as_secs()),
+            None => write!(f, "-;"),
+        }
+    }
+}
+
+/// Print every progress update on one line of stderr until the sender is dropped
+pub async fn display_progress(mut progress: watch::Receiver<Progress>) {
+    loop {
+        let line = progress.borrow_and_update().to_string();
+        {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[2K{line}");
+            let _ = stderr.flush();
+        }
+        if progress.changed().await.is_err() {
+            break;
+        }
+    }
+    eprintln!();
+}
+
+/// Drive [`display_progress`] off a sender that replaces `sender` and forwards every update to it
+///
+/// The bar ends once the replacement is dropped, even if the caller keeps `sender` alive.
+pub(crate) fn spawn_progress_bar(sender: &mut Option<watch::Sender<Progress>>) -> JoinHandle<()> {
+    let (bar, progress) = watch::channel(Progress::default());
+    let forward = sender.replace(bar);
+    let mut updates = progress.clone();
+    tokio::spawn(async move {
+        let forwarding = async {
+            let Some(forward) = forward else {
+                return;
+            };
+            while updates.changed().await.is_ok() {
+                forward.send_replace(*updates.borrow_and_update());
+            }
+        };
+        tokio::join!(display_progress(progress), forwarding);
+    })
+}
+
+/// Accumulate the bytes of a transfer and publish them at most once per sampling interval
+pub(crate) struct ProgressTracker {
+    sender: Option<watch::Sender<Progress>>,
+    progress: Progress,
+    sample_time: Instant,
+    sample_bytes: u64,
+}
+impl ProgressTracker {
+    pub fn new(sender: Option<watch::Sender<Progress>>, total: u64) -> Self {
+        let this = Self {
+            sender,
+            progress: Progress {
+                bytes: 0,
+                total,
+                rate: 0.,
+            },
+            sample_time: Instant::now(),
+            sample_bytes: 0,
+        };
+        this.publish();
+        this
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        self.progress.bytes += bytes;
+        let elapsed = self.sample_time.elapsed();
+        let done = self.progress.total <= self.progress.bytes;
+        if elapsed < SAMPLE_INTERVAL && !done {
+            return;
+        }
+        if SAMPLE_INTERVAL <= elapsed {
+            let sampled = self.progress.bytes - self.sample_bytes;
+            self.progress.rate = sampled as f64 / elapsed.as_secs_f64();
+            self.sample_time = Instant::now();
+            self.sample_bytes = self.progress.bytes;
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        if let Some(sender) = &self.sender {
+            sender.send_replace(self.progress);
+        }
+    }
+}
+
+/// Report everything read through it to a [`ProgressTracker`]
+pub(crate) struct Tracked<'a, R> {
+    read: R,
+    tracker: &'a mut ProgressTracker,
+}
+impl<'a, R> Tracked<'a, R> {
+    pub fn new(read: R, tracker: &'a mut ProgressTracker) -> Self {
+        Self { read, tracker }
+    }
+}
+impl<R> AsyncRead for Tracked<'_, R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.read).poll_read(cx, buf))?;
+        let bytes = buf.filled().len() - filled;
+        self.tracker.add(bytes as u64);
+        Poll::Ready(Ok(()))
+    }
+}
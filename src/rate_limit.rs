@@ -0,0 +1,153 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// Allow `rate` bytes per second on average and up to `burst` bytes at once
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate.max(1) as f64,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Wait until at least one byte is allowed and return how many of `wanted` bytes are
+    fn poll_acquire(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if 1. <= self.tokens {
+                return Poll::Ready(wanted.min(self.tokens as usize));
+            }
+            let wait = (1. - self.tokens) / self.rate;
+            self.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(wait))));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Build the token bucket for an optional rate limit
+///
+/// The burst defaults to one second worth of traffic.
+pub fn token_bucket(rate_limit: Option<u64>, burst: Option<u64>) -> Option<TokenBucket> {
+    rate_limit.map(|rate| TokenBucket::new(rate, burst.unwrap_or(rate)))
+}
+
+/// Throttle the inner stream in either direction if a token bucket is given
+#[derive(Debug)]
+pub struct RateLimited<T> {
+    inner: T,
+    bucket: Option<TokenBucket>,
+}
+impl<T> RateLimited<T> {
+    pub fn new(inner: T, bucket: Option<TokenBucket>) -> Self {
+        Self { inner, bucket }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T> AsyncRead for RateLimited<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let Some(bucket) = &mut this.bucket else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let allowed = ready!(bucket.poll_acquire(cx, buf.remaining()));
+
+        let mut limited = buf.take(allowed);
+        let ptr = limited.filled().as_ptr();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        if ptr != limited.filled().as_ptr() {
+            // The bytes below would be assumed initialized in memory the inner reader did not fill
+            return Poll::Ready(Err(std::io::Error::other("reader swapped the read buffer")));
+        }
+        let n = limited.filled().len();
+
+        // SAFETY: `limited` is a view into the unfilled part of `buf` and the inner reader filled its first `n` bytes
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        bucket.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+impl<T> AsyncWrite for RateLimited<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = &mut *self;
+        let Some(bucket) = &mut this.bucket else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let allowed = ready!(bucket.poll_acquire(cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{handshake::Capabilities, FileTransferError};
+
+const MAX_PATH_LEN: u16 = 4096;
+const MAX_MESSAGE_LEN: u16 = 1024;
+const ACCEPTED: u8 = 0;
+
+/// Why the serving peer turned down a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    NotFound,
+    Forbidden,
+}
+impl Rejection {
+    fn to_u8(self) -> u8 {
+        match self {
+            Rejection::NotFound => 1,
+            Rejection::Forbidden => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Rejection::NotFound,
+            2 => Rejection::Forbidden,
+            _ => return None,
+        })
+    }
+}
+impl core::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::NotFound => write!(f, "not found"),
+            Rejection::Forbidden => write!(f, "forbidden"),
+        }
+    }
+}
+
+/// How the peers agree on the path to transfer once they are authenticated
+#[derive(Debug, Clone, Copy)]
+pub enum PathRequest<'a> {
+    /// Name this path under the root of the serving daemon and wait for it to be accepted
+    Send(&'a Path),
+    /// Point the command at the path named by the connecting peer
+    Receive,
+}
+
+impl PathRequest<'_> {
+    /// Announce this side of the request in the handshake
+    pub(crate) fn capability(&self) -> Capabilities {
+        match self {
+            PathRequest::Send(_) => Capabilities::PATH_REQUEST,
+            PathRequest::Receive => Capabilities::DAEMON,
+        }
+    }
+}
+
+/// Name `path` to the serving peer and fail if it is turned down
+pub async fn send_request<R, W>(
+    read: &mut R,
+    write: &mut W,
+    path: &Path,
+) -> Result<(), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_remote_path(write, path).await?;
+    read_response(read).await
+}
+
+/// Name the path relative to the root of the serving peer that the transfer is about
+pub async fn write_remote_path<W>(write: &mut W, path: &Path) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let path = path.as_os_str().as_bytes();
+    let path_len = u16::try_from(path.len())
+        .ok()
+        .filter(|len| *len <= MAX_PATH_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "remote path too long"))?;
+    write.write_u16(path_len).await?;
+    write.write_all(path).await?;
+    write.flush().await?;
+    Ok(())
+}
+
+pub async fn read_remote_path<R>(read: &mut R) -> Result<PathBuf, FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let path_len = read.read_u16().await?;
+    if MAX_PATH_LEN < path_len {
+        return Err(FileTransferError::protocol_violation(format!(
+            "remote path too long: {path_len} bytes"
+        )));
+    }
+    let mut path = vec![0; usize::from(path_len)];
+    read.read_exact(&mut path).await?;
+    Ok(PathBuf::from(OsStr::from_bytes(&path)))
+}
+
+/// Let the connecting peer go ahead with the transfer
+pub async fn write_accepted<W>(write: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write.write_u8(ACCEPTED).await?;
+    write.flush().await?;
+    Ok(())
+}
+
+/// Turn down the request of the connecting peer
+pub async fn write_rejected<W>(write: &mut W, reason: Rejection, message: &str) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut message = message.as_bytes();
+    message = &message[..message.len().min(usize::from(MAX_MESSAGE_LEN))];
+    write.write_u8(reason.to_u8()).await?;
+    write.write_u16(message.len() as u16).await?;
+    write.write_all(message).await?;
+    write.flush().await?;
+    Ok(())
+}
+
+/// Fail with [`FileTransferError::Rejected`] if the serving peer turned down the request
+pub async fn read_response<R>(read: &mut R) -> Result<(), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let status = read.read_u8().await?;
+    if status == ACCEPTED {
+        return Ok(());
+    }
+    let reason = Rejection::from_u8(status).ok_or_else(|| {
+        FileTransferError::protocol_violation(format!("invalid response status: {status}"))
+    })?;
+    let message_len = read.read_u16().await?;
+    if MAX_MESSAGE_LEN < message_len {
+        return Err(FileTransferError::protocol_violation(format!(
+            "rejection message too long: {message_len} bytes"
+        )));
+    }
+    let mut message = vec![0; usize::from(message_len)];
+    read.read_exact(&mut message).await?;
+    let message = String::from_utf8_lossy(&message).into_owned();
+    Err(FileTransferError::Rejected { reason, message })
+}
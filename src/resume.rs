@@ -0,0 +1,75 @@
+use std::io::{self, SeekFrom};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// What the puller already has of the output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeRequest {
+    pub offset: u64,
+    pub digest: [u8; blake3::OUT_LEN],
+}
+impl ResumeRequest {
+    /// Also return the hasher fed with the partial file for resuming the whole-file digest
+    pub async fn from_partial_file(file: &mut File) -> io::Result<(Self, blake3::Hasher)> {
+        let offset = file.metadata().await?.len();
+        let hasher = prefix_hasher(file, offset).await?;
+        let digest = *hasher.finalize().as_bytes();
+        Ok((Self { offset, digest }, hasher))
+    }
+
+    pub async fn read_from<R>(read: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let offset = read.read_u64().await?;
+        let mut digest = [0; blake3::OUT_LEN];
+        read.read_exact(&mut digest).await?;
+        Ok(Self { offset, digest })
+    }
+
+    pub async fn write_to<W>(&self, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write.write_u64(self.offset).await?;
+        write.write_all(&self.digest).await?;
+        write.flush().await?;
+        Ok(())
+    }
+
+    /// Return the offset to resume from, or `0` if `file` does not start with the puller's prefix
+    ///
+    /// Also return the hasher fed with the skipped prefix.
+    pub async fn resume_offset(&self, file: &mut File) -> io::Result<(u64, blake3::Hasher)> {
+        let bytes = file.metadata().await?.len();
+        if bytes < self.offset {
+            return Ok((0, blake3::Hasher::new()));
+        }
+        let hasher = prefix_hasher(file, self.offset).await?;
+        if hasher.finalize().as_bytes() != &self.digest {
+            return Ok((0, blake3::Hasher::new()));
+        }
+        Ok((self.offset, hasher))
+    }
+}
+
+/// Hash the first `bytes` bytes of `file`
+///
+/// The file cursor is left at the end of the prefix.
+pub async fn prefix_hasher(file: &mut File, bytes: u64) -> io::Result<blake3::Hasher> {
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut prefix = file.take(bytes);
+    let mut buf = vec![0; 1024 * 64];
+    loop {
+        let n = prefix.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher)
+}
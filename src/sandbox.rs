@@ -0,0 +1,188 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// A directory that paths named by the peer are confined to
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+impl Sandbox {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Join `path` to the root
+    ///
+    /// Fail with [`io::ErrorKind::PermissionDenied`] if `path` is not relative, climbs up with `..`,
+    /// or passes through a symlink leading out of the root or to nothing.
+    pub async fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        if !is_confined(path) {
+            return Err(escape(path));
+        }
+        let joined = self.root.join(path);
+        let root = match tokio::fs::canonicalize(&self.root).await {
+            Ok(root) => root,
+            // Nothing under a missing root can be a symlink
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(joined),
+            Err(e) => return Err(e),
+        };
+
+        // Where the deepest existing ancestor really is decides where the path ends up
+        let mut existing = joined.as_path();
+        let real = loop {
+            match tokio::fs::canonicalize(existing).await {
+                Ok(real) => break real,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // A dangling symlink would create its target wherever that is
+                    if tokio::fs::symlink_metadata(existing)
+                        .await
+                        .is_ok_and(|metadata| metadata.is_symlink())
+                    {
+                        return Err(escape(path));
+                    }
+                    existing = existing.parent().ok_or(e)?;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if !real.starts_with(&root) {
+            return Err(escape(path));
+        }
+        Ok(joined)
+    }
+}
+
+/// Whether `path` is non-empty and made of plain names only
+pub fn is_confined(path: &Path) -> bool {
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn escape(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{}: outside of the root", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    struct Tree {
+        dir: tempfile::TempDir,
+        sandbox: Sandbox,
+    }
+    impl Tree {
+        /// A root holding `dir/file` next to an `outside` directory
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(dir.path().join("root/dir")).unwrap();
+            std::fs::write(dir.path().join("root/dir/file"), b"inside").unwrap();
+            std::fs::create_dir(dir.path().join("outside")).unwrap();
+            let sandbox = Sandbox::new(dir.path().join("root"));
+            Self { dir, sandbox }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.path().join("root")
+        }
+
+        async fn assert_escapes(&self, path: impl AsRef<Path>) {
+            let path = path.as_ref();
+            let e = self.sandbox.resolve(path).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied, "{path:?}: {e}");
+        }
+    }
+
+    #[test]
+    fn confined_paths() {
+        assert!(is_confined(Path::new("file")));
+        assert!(is_confined(Path::new("dir/file")));
+        for path in ["", ".", "..", "dir/../file", "./file", "/etc/passwd", "/"] {
+            assert!(!is_confined(Path::new(path)), "{path:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn existing_path() {
+        let tree = Tree::new();
+        let resolved = tree.sandbox.resolve(Path::new("dir/file")).await.unwrap();
+        assert_eq!(resolved, tree.root().join("dir/file"));
+    }
+
+    #[tokio::test]
+    async fn missing_path_under_an_existing_ancestor() {
+        let tree = Tree::new();
+        let resolved = tree
+            .sandbox
+            .resolve(Path::new("dir/new/file"))
+            .await
+            .unwrap();
+        assert_eq!(resolved, tree.root().join("dir/new/file"));
+    }
+
+    #[tokio::test]
+    async fn traversal() {
+        let tree = Tree::new();
+        tree.assert_escapes("../outside").await;
+        tree.assert_escapes("dir/../../outside").await;
+        tree.assert_escapes("dir/..").await;
+    }
+
+    #[tokio::test]
+    async fn absolute_path() {
+        let tree = Tree::new();
+        tree.assert_escapes(tree.dir.path().join("outside")).await;
+        tree.assert_escapes(tree.root().join("dir/file")).await;
+    }
+
+    #[tokio::test]
+    async fn root_itself() {
+        let tree = Tree::new();
+        tree.assert_escapes("").await;
+        tree.assert_escapes(".").await;
+    }
+
+    #[tokio::test]
+    async fn symlink_out_of_the_root() {
+        let tree = Tree::new();
+        symlink(tree.dir.path().join("outside"), tree.root().join("link")).unwrap();
+        tree.assert_escapes("link").await;
+        tree.assert_escapes("link/new").await;
+    }
+
+    #[tokio::test]
+    async fn symlink_within_the_root() {
+        let tree = Tree::new();
+        symlink("dir", tree.root().join("link")).unwrap();
+        let resolved = tree.sandbox.resolve(Path::new("link/file")).await.unwrap();
+        assert_eq!(resolved, tree.root().join("link/file"));
+    }
+
+    #[tokio::test]
+    async fn dangling_symlink() {
+        let tree = Tree::new();
+        let target = tree.dir.path().join("outside/created");
+        symlink(&target, tree.root().join("link")).unwrap();
+        tree.assert_escapes("link").await;
+        symlink("missing", tree.root().join("dir/link")).unwrap();
+        tree.assert_escapes("dir/link").await;
+    }
+
+    #[tokio::test]
+    async fn root_behind_a_symlink() {
+        let tree = Tree::new();
+        let link = tree.dir.path().join("link");
+        symlink(tree.root(), &link).unwrap();
+        let sandbox = Sandbox::new(&link);
+        let resolved = sandbox.resolve(Path::new("dir/file")).await.unwrap();
+        assert_eq!(resolved, link.join("dir/file"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs, io,
+    io::SeekFrom,
+    os::fd::{AsRawFd, RawFd},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{progress::ProgressTracker, FileTransferError};
+
+const BUF_LEN: usize = 1024 * 64;
+
+const END: u8 = 0;
+const DATA: u8 = 1;
+
+static ZEROS: [u8; BUF_LEN] = [0; BUF_LEN];
+
+/// Offsets and lengths of the parts of the first `len` bytes of `file` that are not holes
+///
+/// The whole file is one extent on file systems that cannot report holes.
+fn data_extents(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut extents = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(fd, offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // No data after `offset`
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && offset == 0 => {
+                return Ok(vec![(0, len)]);
+            }
+            Err(e) => return Err(e),
+        };
+        if len <= start {
+            break;
+        }
+        let end = seek(fd, start, libc::SEEK_HOLE)?.min(len);
+        extents.push((start, end - start));
+        offset = end;
+    }
+    Ok(extents)
+}
+
+fn seek(fd: RawFd, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))?;
+    let position = unsafe { libc::lseek(fd, offset, whence) };
+    if position < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(position as u64)
+}
+
+fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    while 0 < len {
+        let n = len.min(BUF_LEN as u64);
+        hasher.update(&ZEROS[..n as usize]);
+        len -= n;
+    }
+}
+
+/// Send the data extents of the first `bytes` bytes of `source`, leaving out its holes
+///
+/// Return the number of bytes covered, holes included, and the digest of the whole content.
+pub async fn send_sparse<W>(
+    mut source: File,
+    bytes: u64,
+    progress: &mut ProgressTracker,
+    write: &mut W,
+) -> Result<(u64, blake3::Hash), FileTransferError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let std_source = source.try_clone().await?.into_std().await;
+    let extents = tokio::task::spawn_blocking(move || data_extents(&std_source, bytes))
+        .await
+        .map_err(io::Error::from)??;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; BUF_LEN];
+    let mut position = 0;
+    for (offset, len) in extents {
+        hash_zeros(&mut hasher, offset - position);
+        progress.add(offset - position);
+
+        write.write_u8(DATA).await?;
+        write.write_u64(offset).await?;
+        write.write_u64(len).await?;
+        source.seek(SeekFrom::Start(offset)).await?;
+        let mut remaining = len;
+        while 0 < remaining {
+            let n = remaining.min(BUF_LEN as u64) as usize;
+            let n = source.read(&mut buf[..n]).await?;
+            if n == 0 {
+                // The extent was announced so the stream cannot continue
+                return Err(FileTransferError::FileChanged {
+                    expected: bytes,
+                    actual: offset + len - remaining,
+                });
+            }
+            hasher.update(&buf[..n]);
+            write.write_all(&buf[..n]).await?;
+            progress.add(n as u64);
+            remaining -= n as u64;
+        }
+        position = offset + len;
+    }
+    hash_zeros(&mut hasher, bytes - position);
+    progress.add(bytes - position);
+    write.write_u8(END).await?;
+
+    Ok((bytes, hasher.finalize()))
+}
+
+/// Write the extents sent by [`send_sparse`] to `file` and leave holes between them
+///
+/// `file` is expected to be empty and ends up `bytes` long.
+///
+/// Return the number of bytes of the file, holes included, and the digest of the whole content.
+pub async fn receive_sparse<R>(
+    read: &mut R,
+    bytes: u64,
+    file: &mut File,
+    progress: &mut ProgressTracker,
+) -> Result<(u64, blake3::Hash), FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; BUF_LEN];
+    let mut position = 0;
+    loop {
+        match read.read_u8().await? {
+            END => break,
+            DATA => {
+                let offset = read.read_u64().await?;
+                let len = read.read_u64().await?;
+                let end = offset.checked_add(len).filter(|end| *end <= bytes);
+                let Some(end) = end.filter(|_| position <= offset) else {
+                    return Err(FileTransferError::protocol_violation(format!(
+                        "invalid extent: {len} bytes at {offset} after {position} of {bytes} bytes"
+                    )));
+                };
+                hash_zeros(&mut hasher, offset - position);
+                progress.add(offset - position);
+
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut remaining = len;
+                while 0 < remaining {
+                    let n = remaining.min(BUF_LEN as u64) as usize;
+                    read.read_exact(&mut buf[..n]).await?;
+                    hasher.update(&buf[..n]);
+                    file.write_all(&buf[..n]).await?;
+                    progress.add(n as u64);
+                    remaining -= n as u64;
+                }
+                position = end;
+            }
+            op => {
+                return Err(FileTransferError::protocol_violation(format!(
+                    "invalid sparse op: {op}"
+                )))
+            }
+        }
+    }
+    hash_zeros(&mut hasher, bytes - position);
+    progress.add(bytes - position);
+    file.set_len(bytes).await?;
+
+    Ok((bytes, hasher.finalize()))
+}
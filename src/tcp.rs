@@ -0,0 +1,161 @@
+use std::{net::SocketAddr, path::Path};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    request::PathRequest, ClientTlsArgs, FileTransferCommand, FileTransferError, FileTransferStats,
+    ServerTlsArgs,
+};
+
+/// Accept one connection on `listen` and perform `command` over it
+pub async fn serve(
+    listen: SocketAddr,
+    tls: &ServerTlsArgs,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    let acceptor = tls.acceptor()?;
+    let listener = TcpListener::bind(listen).await?;
+    let (stream, _) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    match acceptor {
+        Some(acceptor) => perform_stream(acceptor.accept(stream).await?, None, command).await,
+        None => perform_tcp(stream, None, command).await,
+    }
+}
+
+/// Connect to `host` on `port` and perform `command` over the connection
+///
+/// `remote_path` names the file under the root of a [`daemon`](crate::daemon) to transfer.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    tls: &ClientTlsArgs,
+    remote_path: Option<&Path>,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    let connector = tls.connector()?;
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+    match connector {
+        Some(connector) => {
+            let server_name = tls.server_name(host)?;
+            let stream = connector.connect(server_name, stream).await?;
+            perform_stream(stream, remote_path, command).await
+        }
+        None => perform_tcp(stream, remote_path, command).await,
+    }
+}
+
+/// Accept `streams` connections on `listen` and perform `command` over all of them at once
+///
+/// See [`FileTransferCommand::perform_parallel`].
+pub async fn serve_parallel(
+    listen: SocketAddr,
+    tls: &ServerTlsArgs,
+    streams: usize,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    let acceptor = tls.acceptor()?;
+    let listener = TcpListener::bind(listen).await?;
+    let mut tcp_streams = vec![];
+    let mut tls_streams = vec![];
+    for _ in 0..streams {
+        let (stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        match &acceptor {
+            Some(acceptor) => tls_streams.push(acceptor.accept(stream).await?),
+            None => tcp_streams.push(stream),
+        }
+    }
+    match acceptor {
+        Some(_) => perform_parallel(tls_streams, command).await,
+        None => perform_parallel(tcp_streams, command).await,
+    }
+}
+
+/// Open `streams` connections to `host` on `port` and perform `command` over all of them at once
+///
+/// See [`FileTransferCommand::perform_parallel`].
+pub async fn connect_parallel(
+    host: &str,
+    port: u16,
+    tls: &ClientTlsArgs,
+    streams: usize,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    let connector = tls.connector()?;
+    let mut tcp_streams = vec![];
+    let mut tls_streams = vec![];
+    for _ in 0..streams {
+        let stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        match &connector {
+            Some(connector) => {
+                let server_name = tls.server_name(host)?;
+                tls_streams.push(connector.connect(server_name, stream).await?);
+            }
+            None => tcp_streams.push(stream),
+        }
+    }
+    match connector {
+        Some(_) => perform_parallel(tls_streams, command).await,
+        None => perform_parallel(tcp_streams, command).await,
+    }
+}
+
+async fn perform_parallel<S>(
+    streams: Vec<S>,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let streams = streams.into_iter().map(tokio::io::split).collect();
+    let result = command.perform_parallel(streams).await?;
+    for mut write in result.write {
+        write.shutdown().await?;
+    }
+    Ok(result.stats)
+}
+
+/// Same as [`perform_stream`] letting the kernel move file content to and from the socket
+async fn perform_tcp(
+    stream: TcpStream,
+    remote_path: Option<&Path>,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError> {
+    let request = remote_path.map(PathRequest::Send);
+    let mut result = command.perform_tcp_request(stream, request).await?;
+    result.write.shutdown().await?;
+    Ok(result.stats)
+}
+
+async fn perform_stream<S>(
+    stream: S,
+    remote_path: Option<&Path>,
+    command: &FileTransferCommand,
+) -> Result<FileTransferStats, FileTransferError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(stream);
+    perform_split(read, write, command, remote_path.map(PathRequest::Send)).await
+}
+
+pub(crate) async fn perform_split<R, W>(
+    read: R,
+    write: W,
+    command: &FileTransferCommand,
+    request: Option<PathRequest<'_>>,
+) -> Result<FileTransferStats, FileTransferError>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let mut result = command.perform_request(read, write, request).await?;
+    result.write.shutdown().await?;
+    Ok(result.stats)
+}
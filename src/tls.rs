@@ -0,0 +1,138 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::Args;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// TLS settings of the serving peer
+#[derive(Debug, Clone, Default, Args)]
+pub struct ServerTlsArgs {
+    /// PEM certificate chain to present to clients; enables TLS
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates that client certificates must be signed by; requires clients to present one
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+impl ServerTlsArgs {
+    /// Return `None` if TLS is not enabled
+    pub fn acceptor(&self) -> io::Result<Option<TlsAcceptor>> {
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?;
+        let builder = match &self.tls_client_ca {
+            Some(client_ca) => {
+                let roots = root_cert_store(client_ca)?;
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider())
+                    .build()
+                    .map_err(invalid_input)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(cert_chain(cert)?, private_key(key)?)
+            .map_err(invalid_input)?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+/// TLS settings of the connecting peer
+#[derive(Debug, Clone, Default, Args)]
+pub struct ClientTlsArgs {
+    /// PEM CA certificates that the server certificate must be signed by; enables TLS
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+    /// PEM certificate chain to present to the server
+    #[arg(long, requires = "tls_ca", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Name the server certificate must be valid for; defaults to the host
+    #[arg(long, requires = "tls_ca")]
+    pub tls_server_name: Option<String>,
+}
+impl ClientTlsArgs {
+    /// Return `None` if TLS is not enabled
+    pub fn connector(&self) -> io::Result<Option<TlsConnector>> {
+        let Some(ca) = &self.tls_ca else {
+            return Ok(None);
+        };
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_root_certificates(root_cert_store(ca)?);
+        let config = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(cert_chain(cert)?, private_key(key)?)
+                .map_err(invalid_input)?,
+            _ => builder.with_no_client_auth(),
+        };
+        Ok(Some(TlsConnector::from(Arc::new(config))))
+    }
+
+    /// The name to verify the server certificate against when connecting to `host`
+    pub fn server_name(&self, host: &str) -> io::Result<ServerName<'static>> {
+        let name = self.tls_server_name.as_deref().unwrap_or(host);
+        ServerName::try_from(name.to_owned()).map_err(invalid_input)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn cert_chain(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_pem(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid_pem(path, e))
+}
+
+fn root_cert_store(path: &Path) -> io::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in cert_chain(path)? {
+        roots.add(cert).map_err(invalid_input)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn invalid_pem(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {e}", path.display()),
+    )
+}
+
+fn invalid_input<E>(e: E) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
@@ -0,0 +1,98 @@
+use std::io;
+
+use clap::Args;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+const DEFAULT_BUFFER_SIZE: u32 = 1024 * 256;
+const DEFAULT_READ_AHEAD: u32 = 2;
+
+/// How content is copied between the file and the stream
+#[derive(Debug, Clone, Args)]
+pub struct TuningArgs {
+    /// Size in bytes of each buffer content is copied through
+    #[arg(
+        long,
+        default_value_t = DEFAULT_BUFFER_SIZE,
+        value_parser = clap::value_parser!(u32).range(4096..=64 * 1024 * 1024),
+    )]
+    pub buffer_size: u32,
+    /// Number of buffers read ahead while the previous one is being written
+    #[arg(
+        long,
+        default_value_t = DEFAULT_READ_AHEAD,
+        value_parser = clap::value_parser!(u32).range(1..=64),
+    )]
+    pub read_ahead: u32,
+    /// Fill each buffer before writing it instead of writing whatever each read returned
+    #[arg(long)]
+    pub coalesce_writes: bool,
+}
+impl Default for TuningArgs {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            read_ahead: DEFAULT_READ_AHEAD,
+            coalesce_writes: false,
+        }
+    }
+}
+impl TuningArgs {
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size as usize
+    }
+
+    /// Copy `read` to `write` while reading ahead into the next buffers
+    ///
+    /// Return the number of bytes copied.
+    pub async fn copy<R, W>(&self, read: &mut R, write: &mut W) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let depth = self.read_ahead as usize;
+        let (full_tx, mut full_rx) = mpsc::channel::<Vec<u8>>(depth);
+        let (empty_tx, mut empty_rx) = mpsc::channel(depth + 1);
+        for _ in 0..=depth {
+            empty_tx.try_send(vec![]).unwrap();
+        }
+
+        let buffer_size = self.buffer_size();
+        let coalesce = self.coalesce_writes;
+        let reading = async move {
+            while let Some(mut buf) = empty_rx.recv().await {
+                buf.resize(buffer_size, 0);
+                let mut len = 0;
+                loop {
+                    let n = read.read(&mut buf[len..]).await?;
+                    len += n;
+                    if n == 0 || len == buf.len() || !coalesce {
+                        break;
+                    }
+                }
+                if len == 0 {
+                    break;
+                }
+                buf.truncate(len);
+                if full_tx.send(buf).await.is_err() {
+                    break;
+                }
+            }
+            io::Result::Ok(())
+        };
+        let writing = async move {
+            let mut copied = 0;
+            while let Some(buf) = full_rx.recv().await {
+                write.write_all(&buf).await?;
+                copied += buf.len() as u64;
+                // The reader stops taking buffers back at the end of `read`
+                let _ = empty_tx.send(buf).await;
+            }
+            io::Result::Ok(copied)
+        };
+        let ((), copied) = tokio::try_join!(reading, writing)?;
+        Ok(copied)
+    }
+}
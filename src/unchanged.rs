@@ -0,0 +1,139 @@
+use std::{io, os::unix::fs::MetadataExt, path::Path};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{resume::prefix_hasher, FileTransferError};
+
+const SEND: u8 = 0;
+const SKIP: u8 = 1;
+
+/// What the puller reports of the existing output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Only compared if the puller preserves modification times
+    pub modified: Option<(i64, u32)>,
+    pub digest: [u8; blake3::OUT_LEN],
+}
+impl Fingerprint {
+    /// Return `None` if there is no file at `path`
+    pub async fn of_path(path: &Path, modified: bool) -> io::Result<Option<Self>> {
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let size = metadata.len();
+        let modified = modified.then(|| (metadata.mtime(), metadata.mtime_nsec() as u32));
+        let digest = *prefix_hasher(&mut file, size).await?.finalize().as_bytes();
+        Ok(Some(Self {
+            size,
+            modified,
+            digest,
+        }))
+    }
+
+    /// Whether `file` has the same content and, if reported, modification time
+    ///
+    /// The content is only hashed if everything else matches.
+    pub async fn matches(&self, file: &mut File) -> io::Result<bool> {
+        let metadata = file.metadata().await?;
+        if metadata.len() != self.size {
+            return Ok(false);
+        }
+        if let Some(modified) = self.modified {
+            if modified != (metadata.mtime(), metadata.mtime_nsec() as u32) {
+                return Ok(false);
+            }
+        }
+        let hasher = prefix_hasher(file, self.size).await?;
+        Ok(hasher.finalize().as_bytes() == &self.digest)
+    }
+
+    pub async fn write_to<W>(fingerprint: Option<&Self>, write: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match fingerprint {
+            Some(fingerprint) => {
+                write.write_u8(1).await?;
+                write.write_u64(fingerprint.size).await?;
+                match fingerprint.modified {
+                    Some((secs, nanos)) => {
+                        write.write_u8(1).await?;
+                        write.write_i64(secs).await?;
+                        write.write_u32(nanos).await?;
+                    }
+                    None => write.write_u8(0).await?,
+                }
+                write.write_all(&fingerprint.digest).await?;
+            }
+            None => write.write_u8(0).await?,
+        }
+        write.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read_from<R>(read: &mut R) -> Result<Option<Self>, FileTransferError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if !read_flag(read).await? {
+            return Ok(None);
+        }
+        let size = read.read_u64().await?;
+        let modified = match read_flag(read).await? {
+            true => Some((read.read_i64().await?, read.read_u32().await?)),
+            false => None,
+        };
+        let mut digest = [0; blake3::OUT_LEN];
+        read.read_exact(&mut digest).await?;
+        Ok(Some(Self {
+            size,
+            modified,
+            digest,
+        }))
+    }
+}
+
+async fn read_flag<R>(read: &mut R) -> Result<bool, FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    match read.read_u8().await? {
+        0 => Ok(false),
+        1 => Ok(true),
+        flag => Err(FileTransferError::protocol_violation(format!(
+            "invalid flag: {flag}"
+        ))),
+    }
+}
+
+/// Tell the puller whether the payload follows
+pub async fn write_verdict<W>(write: &mut W, skip: bool) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write.write_u8(if skip { SKIP } else { SEND }).await?;
+    write.flush().await
+}
+
+pub async fn read_verdict<R>(read: &mut R) -> Result<bool, FileTransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    match read.read_u8().await? {
+        SEND => Ok(false),
+        SKIP => Ok(true),
+        verdict => Err(FileTransferError::protocol_violation(format!(
+            "invalid skip verdict: {verdict}"
+        ))),
+    }
+}
@@ -0,0 +1,594 @@
+use std::io;
+
+use clap::Args;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite},
+};
+
+/// How files are read and written through io_uring
+///
+/// Without the `io-uring` feature or outside Linux, files always go through the blocking thread pool
+/// and `uring_depth` is ignored.
+#[derive(Debug, Clone, Args)]
+pub struct UringArgs {
+    /// Number of file reads or writes kept in flight through io_uring
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..=1024))]
+    pub uring_depth: u32,
+}
+impl Default for UringArgs {
+    fn default() -> Self {
+        Self { uring_depth: 32 }
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl UringArgs {
+    /// Read `len` bytes of `file` from `offset` ahead of the consumer
+    ///
+    /// Return `None` if io_uring is not available.
+    pub async fn reader(
+        &self,
+        file: &File,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Option<impl AsyncRead + Send + Unpin>> {
+        let file = file.try_clone().await?.into_std().await;
+        Ok(ring::UringReader::new(file, offset, len, self.uring_depth).ok())
+    }
+
+    /// Write to `file` from `offset` on behind the producer
+    ///
+    /// Return `None` if io_uring is not available.
+    pub async fn writer(
+        &self,
+        file: &File,
+        offset: u64,
+    ) -> io::Result<Option<impl AsyncWrite + Send + Unpin>> {
+        let file = file.try_clone().await?.into_std().await;
+        Ok(ring::UringWriter::new(file, offset, self.uring_depth).ok())
+    }
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+impl UringArgs {
+    pub async fn reader(
+        &self,
+        _file: &File,
+        _offset: u64,
+        _len: u64,
+    ) -> io::Result<Option<impl AsyncRead + Send + Unpin>> {
+        Ok(None::<tokio::io::Empty>)
+    }
+
+    pub async fn writer(
+        &self,
+        _file: &File,
+        _offset: u64,
+    ) -> io::Result<Option<impl AsyncWrite + Send + Unpin>> {
+        Ok(None::<tokio::io::Sink>)
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+    use std::{
+        collections::BTreeMap,
+        fs, io,
+        os::fd::AsRawFd,
+        pin::Pin,
+        sync::{mpsc as std_mpsc, Arc},
+        task::{ready, Context, Poll},
+    };
+
+    use io_uring::{cqueue, opcode, types, IoUring};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        sync::mpsc,
+    };
+
+    const BUF_LEN: usize = 1024 * 128;
+
+    /// Memory registered with a ring
+    ///
+    /// Each buffer is used either by the kernel or by the stream at a time, which the channels between them hand over.
+    struct Buffers {
+        bufs: Vec<*mut [u8]>,
+    }
+    unsafe impl Send for Buffers {}
+    unsafe impl Sync for Buffers {}
+    impl Buffers {
+        fn new(count: u32) -> Self {
+            let bufs = (0..count)
+                .map(|_| Box::into_raw(vec![0; BUF_LEN].into_boxed_slice()))
+                .collect();
+            Self { bufs }
+        }
+
+        fn len(&self) -> usize {
+            self.bufs.len()
+        }
+
+        fn ptr(&self, index: u16) -> *mut u8 {
+            self.bufs[usize::from(index)].cast()
+        }
+
+        /// # Safety
+        ///
+        /// The caller must own buffer `index`.
+        unsafe fn get(&self, index: u16, len: usize) -> &[u8] {
+            std::slice::from_raw_parts(self.ptr(index), len)
+        }
+
+        /// # Safety
+        ///
+        /// The caller must own buffer `index`.
+        #[allow(clippy::mut_from_ref)]
+        unsafe fn get_mut(&self, index: u16, start: usize, len: usize) -> &mut [u8] {
+            std::slice::from_raw_parts_mut(self.ptr(index).add(start), len)
+        }
+
+        fn register(&self, ring: &IoUring) -> io::Result<()> {
+            let iovecs: Vec<libc::iovec> = (0..self.bufs.len())
+                .map(|index| libc::iovec {
+                    iov_base: self.ptr(index as u16).cast(),
+                    iov_len: BUF_LEN,
+                })
+                .collect();
+            unsafe { ring.submitter().register_buffers(&iovecs) }
+        }
+    }
+    impl Drop for Buffers {
+        fn drop(&mut self) {
+            for buf in self.bufs.drain(..) {
+                drop(unsafe { Box::from_raw(buf) });
+            }
+        }
+    }
+
+    fn setup(depth: u32) -> io::Result<(IoUring, Arc<Buffers>)> {
+        let ring = IoUring::new(depth)?;
+        let buffers = Arc::new(Buffers::new(depth));
+        buffers.register(&ring)?;
+        Ok((ring, buffers))
+    }
+
+    /// Wait for at least one completion and collect the completions
+    fn complete(ring: &mut IoUring) -> io::Result<Vec<cqueue::Entry>> {
+        loop {
+            match ring.submit_and_wait(1) {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ring.completion().collect())
+    }
+
+    struct Chunk {
+        index: u16,
+        len: usize,
+    }
+
+    /// Stream content read ahead by a ring on its own thread
+    pub struct UringReader {
+        buffers: Arc<Buffers>,
+        ready: mpsc::UnboundedReceiver<io::Result<Chunk>>,
+        free: std_mpsc::Sender<u16>,
+        /// The chunk being consumed and how much of it is
+        current: Option<(Chunk, usize)>,
+    }
+    impl UringReader {
+        pub fn new(file: fs::File, offset: u64, len: u64, depth: u32) -> io::Result<Self> {
+            let (ring, buffers) = setup(depth)?;
+            let (ready_tx, ready) = mpsc::unbounded_channel();
+            let (free, free_rx) = std_mpsc::channel();
+            let driver = ReadAhead {
+                ring,
+                file,
+                buffers: buffers.clone(),
+                offset,
+                end: offset.saturating_add(len),
+                free: free_rx,
+                ready: ready_tx,
+            };
+            std::thread::spawn(move || driver.run());
+            Ok(Self {
+                buffers,
+                ready,
+                free,
+                current: None,
+            })
+        }
+    }
+    impl AsyncRead for UringReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            loop {
+                if let Some((chunk, consumed)) = &mut this.current {
+                    if *consumed < chunk.len {
+                        let n = (chunk.len - *consumed).min(buf.remaining());
+                        let data = unsafe { this.buffers.get(chunk.index, chunk.len) };
+                        buf.put_slice(&data[*consumed..*consumed + n]);
+                        *consumed += n;
+                        return Poll::Ready(Ok(()));
+                    }
+                    let _ = this.free.send(chunk.index);
+                    this.current = None;
+                }
+                match ready!(this.ready.poll_recv(cx)) {
+                    Some(Ok(chunk)) => this.current = Some((chunk, 0)),
+                    Some(Err(e)) => return Poll::Ready(Err(e)),
+                    None => return Poll::Ready(Ok(())),
+                }
+            }
+        }
+    }
+
+    struct ReadAhead {
+        ring: IoUring,
+        file: fs::File,
+        buffers: Arc<Buffers>,
+        offset: u64,
+        end: u64,
+        free: std_mpsc::Receiver<u16>,
+        ready: mpsc::UnboundedSender<io::Result<Chunk>>,
+    }
+    impl ReadAhead {
+        /// Keep every free buffer in flight and hand the completed ones over in file order
+        fn run(mut self) {
+            let mut free: Vec<u16> = (0..self.buffers.len() as u16).collect();
+            // Buffer and requested length of each read by sequence number
+            let mut in_flight = BTreeMap::new();
+            let mut completed = BTreeMap::new();
+            let (mut next_seq, mut emit_seq) = (0, 0);
+            let mut stopped = false;
+            loop {
+                while let Ok(index) = self.free.try_recv() {
+                    free.push(index);
+                }
+                while !stopped && self.offset < self.end {
+                    let Some(index) = free.pop() else {
+                        break;
+                    };
+                    let len = (self.end - self.offset).min(BUF_LEN as u64) as u32;
+                    let read = opcode::ReadFixed::new(
+                        types::Fd(self.file.as_raw_fd()),
+                        self.buffers.ptr(index),
+                        len,
+                        index,
+                    )
+                    .offset(self.offset)
+                    .build()
+                    .user_data(next_seq);
+                    // The queue holds as many entries as there are buffers
+                    unsafe { self.ring.submission().push(&read) }.expect("submission queue full");
+                    in_flight.insert(next_seq, (index, len as usize));
+                    next_seq += 1;
+                    self.offset += u64::from(len);
+                }
+
+                if in_flight.is_empty() {
+                    if stopped || self.offset == self.end {
+                        return;
+                    }
+                    // Everything is read ahead until the stream gives a buffer back
+                    match self.free.recv() {
+                        Ok(index) => free.push(index),
+                        Err(_) => stopped = true,
+                    }
+                    continue;
+                }
+
+                let entries = match complete(&mut self.ring) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        // Without completions the buffers cannot be reused or released safely
+                        let _ = self.ready.send(Err(e));
+                        std::mem::forget(self.buffers);
+                        return;
+                    }
+                };
+                for entry in entries {
+                    completed.insert(entry.user_data(), entry.result());
+                }
+                while let Some(result) = completed.remove(&emit_seq) {
+                    let (index, len) = in_flight.remove(&emit_seq).unwrap();
+                    emit_seq += 1;
+                    if stopped {
+                        continue;
+                    }
+                    if result < 0 {
+                        let _ = self.ready.send(Err(io::Error::from_raw_os_error(-result)));
+                        stopped = true;
+                        continue;
+                    }
+                    let n = result as usize;
+                    if n < len {
+                        // The file got shorter so nothing after this read is valid
+                        stopped = true;
+                    }
+                    if n == 0 {
+                        continue;
+                    }
+                    if self.ready.send(Ok(Chunk { index, len: n })).is_err() {
+                        stopped = true;
+                    }
+                }
+            }
+        }
+    }
+
+    struct WriteRequest {
+        index: u16,
+        offset: u64,
+        len: usize,
+    }
+
+    /// Write behind the stream through a ring on its own thread
+    pub struct UringWriter {
+        buffers: Arc<Buffers>,
+        requests: std_mpsc::Sender<WriteRequest>,
+        done: mpsc::UnboundedReceiver<io::Result<u16>>,
+        free: Vec<u16>,
+        /// The buffer being filled and how much of it is
+        current: Option<(u16, usize)>,
+        offset: u64,
+        outstanding: usize,
+    }
+    impl UringWriter {
+        pub fn new(file: fs::File, offset: u64, depth: u32) -> io::Result<Self> {
+            let (ring, buffers) = setup(depth)?;
+            let (requests, requests_rx) = std_mpsc::channel();
+            let (done_tx, done) = mpsc::unbounded_channel();
+            let driver = WriteBehind {
+                ring,
+                file,
+                buffers: buffers.clone(),
+                requests: requests_rx,
+                done: done_tx,
+            };
+            std::thread::spawn(move || driver.run());
+            Ok(Self {
+                buffers,
+                requests,
+                done,
+                free: (0..depth as u16).collect(),
+                current: None,
+                offset,
+                outstanding: 0,
+            })
+        }
+
+        fn submit(&mut self) -> io::Result<()> {
+            let Some((index, len)) = self.current.take() else {
+                return Ok(());
+            };
+            if len == 0 {
+                self.free.push(index);
+                return Ok(());
+            }
+            let request = WriteRequest {
+                index,
+                offset: self.offset,
+                len,
+            };
+            self.requests
+                .send(request)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            self.offset += len as u64;
+            self.outstanding += 1;
+            Ok(())
+        }
+
+        /// Wait for one write to complete and take its buffer back
+        fn poll_done(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match ready!(self.done.poll_recv(cx)) {
+                Some(Ok(index)) => {
+                    self.free.push(index);
+                    self.outstanding -= 1;
+                    Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => Poll::Ready(Err(e)),
+                None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+    }
+    impl AsyncWrite for UringWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = &mut *self;
+            loop {
+                if let Some((index, filled)) = &mut this.current {
+                    let n = (BUF_LEN - *filled).min(buf.len());
+                    let dest = unsafe { this.buffers.get_mut(*index, *filled, n) };
+                    dest.copy_from_slice(&buf[..n]);
+                    *filled += n;
+                    if *filled == BUF_LEN {
+                        this.submit()?;
+                    }
+                    return Poll::Ready(Ok(n));
+                }
+                match this.free.pop() {
+                    Some(index) => this.current = Some((index, 0)),
+                    None => ready!(this.poll_done(cx))?,
+                }
+            }
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            this.submit()?;
+            while 0 < this.outstanding {
+                ready!(this.poll_done(cx))?;
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    struct WriteBehind {
+        ring: IoUring,
+        file: fs::File,
+        buffers: Arc<Buffers>,
+        requests: std_mpsc::Receiver<WriteRequest>,
+        done: mpsc::UnboundedSender<io::Result<u16>>,
+    }
+    impl WriteBehind {
+        fn run(mut self) {
+            // Offset and written part of the buffer in flight by buffer index
+            let mut in_flight: Vec<Option<(u64, usize, usize)>> = vec![None; self.buffers.len()];
+            let mut pending = vec![];
+            let mut count = 0;
+            loop {
+                if count == 0 && pending.is_empty() {
+                    match self.requests.recv() {
+                        Ok(request) => pending.push(request),
+                        Err(_) => return,
+                    }
+                }
+                while let Ok(request) = self.requests.try_recv() {
+                    pending.push(request);
+                }
+                for request in pending.drain(..) {
+                    let start =
+                        in_flight[usize::from(request.index)].map_or(0, |(_, start, _)| start);
+                    let write = opcode::WriteFixed::new(
+                        types::Fd(self.file.as_raw_fd()),
+                        unsafe { self.buffers.ptr(request.index).add(start) },
+                        (request.len - start) as u32,
+                        request.index,
+                    )
+                    .offset(request.offset + start as u64)
+                    .build()
+                    .user_data(u64::from(request.index));
+                    // The queue holds as many entries as there are buffers
+                    unsafe { self.ring.submission().push(&write) }.expect("submission queue full");
+                    in_flight[usize::from(request.index)] =
+                        Some((request.offset, start, request.len));
+                    count += 1;
+                }
+
+                let entries = match complete(&mut self.ring) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        // Without completions the buffers cannot be reused or released safely
+                        let _ = self.done.send(Err(e));
+                        std::mem::forget(self.buffers);
+                        return;
+                    }
+                };
+                for entry in entries {
+                    count -= 1;
+                    let index = entry.user_data() as u16;
+                    let (offset, start, len) = in_flight[usize::from(index)].take().unwrap();
+                    let result = entry.result();
+                    if result < 0 {
+                        let _ = self.done.send(Err(io::Error::from_raw_os_error(-result)));
+                        continue;
+                    }
+                    if result == 0 {
+                        let _ = self.done.send(Err(io::ErrorKind::WriteZero.into()));
+                        continue;
+                    }
+                    let start = start + result as usize;
+                    if start < len {
+                        // Write the rest of a short write
+                        in_flight[usize::from(index)] = Some((offset, start, len));
+                        pending.push(WriteRequest { index, offset, len });
+                        continue;
+                    }
+                    let _ = self.done.send(Ok(index));
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::*;
+
+        fn content(len: usize) -> Vec<u8> {
+            (0..len).map(|i| (i % 251) as u8).collect()
+        }
+
+        #[tokio::test]
+        async fn reader_round_trip() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            // The last read is shorter than a buffer
+            let content = content(5 * BUF_LEN + 1234);
+            std::fs::write(&path, &content).unwrap();
+
+            for (offset, len) in [
+                (0, content.len()),
+                (100, content.len() - 100),
+                (BUF_LEN + 7, 2 * BUF_LEN),
+                (0, content.len() + BUF_LEN),
+            ] {
+                let file = fs::File::open(&path).unwrap();
+                let mut reader = UringReader::new(file, offset as u64, len as u64, 4).unwrap();
+                let mut read = vec![];
+                reader.read_to_end(&mut read).await.unwrap();
+                let end = (offset + len).min(content.len());
+                assert_eq!(read, content[offset..end], "{len} bytes from {offset}");
+            }
+        }
+
+        #[tokio::test]
+        async fn reader_stops_where_the_file_shrinks() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            let content = content(8 * BUF_LEN);
+            std::fs::write(&path, &content).unwrap();
+
+            // A single buffer keeps the reads after the first one from starting before the truncation
+            let file = fs::File::open(&path).unwrap();
+            let mut reader = UringReader::new(file, 0, content.len() as u64, 1).unwrap();
+            let mut read = vec![0; BUF_LEN];
+            reader.read_exact(&mut read).await.unwrap();
+            let shrunk = 2 * BUF_LEN + 100;
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(shrunk as u64)
+                .unwrap();
+            reader.read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, content[..shrunk]);
+        }
+
+        #[tokio::test]
+        async fn writer_round_trip() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            let prefix = vec![0xff; 1000];
+            std::fs::write(&path, &prefix).unwrap();
+            // The last write is shorter than a buffer
+            let content = content(3 * BUF_LEN + 77);
+
+            let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            let mut writer = UringWriter::new(file, prefix.len() as u64, 2).unwrap();
+            for piece in content.chunks(4099) {
+                writer.write_all(piece).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+
+            let written = std::fs::read(&path).unwrap();
+            assert_eq!(written[..prefix.len()], prefix);
+            assert_eq!(written[prefix.len()..], content);
+        }
+    }
+}
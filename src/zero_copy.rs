@@ -0,0 +1,267 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+};
+#[cfg(target_os = "linux")]
+use std::{
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+#[cfg(target_os = "linux")]
+use tokio::{io::unix::AsyncFd, net::TcpStream};
+
+use crate::progress::ProgressTracker;
+
+#[cfg(target_os = "linux")]
+const CHUNK_LEN: usize = 1024 * 1024;
+#[cfg(target_os = "linux")]
+const PIPE_LEN: libc::c_int = 1024 * 1024;
+
+/// A TCP socket that file content is moved to and from inside the kernel
+///
+/// Only used on Linux, where `sendfile(2)` and `splice(2)` are available.
+#[cfg(target_os = "linux")]
+pub struct Socket {
+    fd: AsyncFd<OwnedFd>,
+    /// Bytes moved past the stream wrappers
+    bytes: AtomicU64,
+}
+#[cfg(target_os = "linux")]
+impl Socket {
+    /// Duplicate the descriptor of `stream` so that it can be waited on independently
+    pub fn new(stream: &TcpStream) -> io::Result<Self> {
+        let fd = stream.as_fd().try_clone_to_owned()?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            bytes: AtomicU64::new(0),
+        })
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Send `len` bytes of `file` starting at `offset` with `sendfile(2)`
+    ///
+    /// Return the number of bytes sent, which is less than `len` only if the file got shorter.
+    pub async fn send_file(
+        &self,
+        file: &fs::File,
+        mut offset: u64,
+        len: u64,
+        progress: &mut ProgressTracker,
+    ) -> io::Result<u64> {
+        let mut sent = 0;
+        while sent < len {
+            let chunk = (len - sent).min(CHUNK_LEN as u64) as usize;
+            let mut guard = self.fd.writable().await?;
+            let n = match guard.try_io(|socket| {
+                let mut off = to_off_t(offset)?;
+                let n = unsafe {
+                    libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut off, chunk)
+                };
+                check(n)
+            }) {
+                Ok(n) => n?,
+                Err(_would_block) => continue,
+            };
+            if n == 0 {
+                break;
+            }
+            offset += n as u64;
+            sent += n as u64;
+            self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+            progress.add(n as u64);
+        }
+        Ok(sent)
+    }
+
+    /// Receive exactly `len` bytes into `file` starting at `offset` with `splice(2)` through a pipe
+    pub async fn receive_file(
+        &self,
+        file: &fs::File,
+        mut offset: u64,
+        len: u64,
+        progress: &mut ProgressTracker,
+    ) -> io::Result<()> {
+        let (pipe_read, pipe_write) = pipe()?;
+        // Writing to the file blocks, so it is left to the blocking threads
+        let sink = Arc::new((pipe_read, file.try_clone()?));
+        let mut received = 0;
+        while received < len {
+            let chunk = (len - received).min(CHUNK_LEN as u64) as usize;
+            let mut guard = self.fd.readable().await?;
+            let n = match guard.try_io(|socket| {
+                let n = unsafe {
+                    libc::splice(
+                        socket.as_raw_fd(),
+                        std::ptr::null_mut(),
+                        pipe_write.as_raw_fd(),
+                        std::ptr::null_mut(),
+                        chunk,
+                        libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+                    )
+                };
+                check(n)
+            }) {
+                Ok(n) => n?,
+                Err(_would_block) => continue,
+            };
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            // Everything in the pipe goes to the file before reading from the socket again
+            let sink = sink.clone();
+            tokio::task::spawn_blocking(move || drain_pipe(&sink.0, &sink.1, offset, n)).await??;
+            offset += n as u64;
+            received += n as u64;
+            self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+            progress.add(n as u64);
+        }
+        Ok(())
+    }
+}
+
+/// Stands in for the socket on other platforms, where content always goes through the stream
+#[cfg(not(target_os = "linux"))]
+pub enum Socket {}
+#[cfg(not(target_os = "linux"))]
+impl Socket {
+    pub fn bytes(&self) -> u64 {
+        match *self {}
+    }
+
+    pub async fn send_file(
+        &self,
+        _file: &fs::File,
+        _offset: u64,
+        _len: u64,
+        _progress: &mut ProgressTracker,
+    ) -> io::Result<u64> {
+        match *self {}
+    }
+
+    pub async fn receive_file(
+        &self,
+        _file: &fs::File,
+        _offset: u64,
+        _len: u64,
+        _progress: &mut ProgressTracker,
+    ) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+/// Feed `len` bytes of `file` starting at `offset` to `hasher`
+///
+/// Blocks on the file reads.
+pub fn hash_file(
+    mut file: fs::File,
+    offset: u64,
+    len: u64,
+    mut hasher: blake3::Hasher,
+) -> io::Result<blake3::Hasher> {
+    file.seek(SeekFrom::Start(offset))?;
+    hasher.update_reader(file.take(len))?;
+    Ok(hasher)
+}
+
+/// Move `len` bytes out of `pipe` into `file` at `offset` with `splice(2)`
+///
+/// Blocks on the file writes.
+#[cfg(target_os = "linux")]
+fn drain_pipe(pipe: &OwnedFd, file: &fs::File, mut offset: u64, mut len: usize) -> io::Result<()> {
+    while 0 < len {
+        let mut off = to_off_t(offset)?;
+        let written = check(unsafe {
+            libc::splice(
+                pipe.as_raw_fd(),
+                std::ptr::null_mut(),
+                file.as_raw_fd(),
+                &mut off,
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        })?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        offset += written as u64;
+        len -= written;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } as isize)?;
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // A larger pipe means fewer round trips; the default size still works
+    unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, PIPE_LEN) };
+    Ok((read, write))
+}
+
+#[cfg(target_os = "linux")]
+fn to_off_t(offset: u64) -> io::Result<libc::off_t> {
+    libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))
+}
+
+#[cfg(target_os = "linux")]
+fn check(n: isize) -> io::Result<usize> {
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn file_round_trip_through_the_kernel() {
+        let dir = tempfile::tempdir().unwrap();
+        let len = 3 * 1024 * 1024 + 123;
+        let content: Vec<u8> = (0..len).map(|i| (i * 7 % 253) as u8).collect();
+        std::fs::write(dir.path().join("source"), &content).unwrap();
+        let source = fs::File::open(dir.path().join("source")).unwrap();
+        let output = fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.path().join("output"))
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+        let (sending, receiving) = (connected.unwrap(), accepted.unwrap().0);
+        let sender = Socket::new(&sending).unwrap();
+        let receiver = Socket::new(&receiving).unwrap();
+
+        let len = len as u64;
+        let mut sent_progress = ProgressTracker::new(None, len);
+        let mut received_progress = ProgressTracker::new(None, len);
+        let (sent, received) = tokio::join!(
+            sender.send_file(&source, 0, len, &mut sent_progress),
+            receiver.receive_file(&output, 0, len, &mut received_progress),
+        );
+        assert_eq!(sent.unwrap(), len);
+        received.unwrap();
+        assert_eq!(sender.bytes(), len);
+        assert_eq!(receiver.bytes(), len);
+
+        assert!(std::fs::read(dir.path().join("output")).unwrap() == content);
+        let hasher = hash_file(output, 0, len, blake3::Hasher::new()).unwrap();
+        assert_eq!(hasher.finalize(), blake3::hash(&content));
+    }
+}
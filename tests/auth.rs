@@ -0,0 +1,128 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use file_transfer::{FileTransferCommand, FileTransferError, PullFileArgs, PushFileArgs};
+use tokio::io::AsyncWrite;
+
+const CONTENT: &[u8] = b"content that must not reach an unauthenticated peer";
+
+/// Keeps a copy of everything written through it
+struct Recording<W> {
+    inner: W,
+    written: Arc<Mutex<Vec<u8>>>,
+}
+impl<W: AsyncWrite + Unpin> AsyncWrite for Recording<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            self.written.lock().unwrap().extend_from_slice(&buf[..n]);
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn psk_file(dir: &Path, name: &str, psk: &str) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, psk).unwrap();
+    path
+}
+
+/// Push [`CONTENT`] with `push_psk` to a puller with `pull_psk`
+///
+/// Return the outcomes of both sides and everything the pusher sent.
+async fn transfer(
+    dir: &Path,
+    push_psk: Option<PathBuf>,
+    pull_psk: Option<PathBuf>,
+) -> (
+    Result<(), FileTransferError>,
+    Result<(), FileTransferError>,
+    Vec<u8>,
+) {
+    let source = dir.join("source");
+    std::fs::write(&source, CONTENT).unwrap();
+    let push = FileTransferCommand::Push(PushFileArgs {
+        psk_file: push_psk,
+        ..PushFileArgs::new(&source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        psk_file: pull_psk,
+        ..PullFileArgs::new(dir.join("output"))
+    });
+
+    let (push_stream, pull_stream) = tokio::io::duplex(1024 * 64);
+    let written = Arc::new(Mutex::new(vec![]));
+    let (push_read, push_write) = tokio::io::split(push_stream);
+    let push_write = Recording {
+        inner: push_write,
+        written: written.clone(),
+    };
+    let (pull_read, pull_write) = tokio::io::split(pull_stream);
+    let (pushed, pulled) = tokio::join!(
+        push.perform(push_read, push_write),
+        pull.perform(pull_read, pull_write),
+    );
+    let written = written.lock().unwrap().clone();
+    (pushed.map(|_| ()), pulled.map(|_| ()), written)
+}
+
+fn assert_rejected(dir: &Path, result: Result<(), FileTransferError>) {
+    match result {
+        Err(FileTransferError::Unauthenticated(_)) => {}
+        other => panic!("expected an authentication error, got {other:?}"),
+    }
+    assert!(!dir.join("output").exists());
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[tokio::test]
+async fn matching_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let psk = psk_file(dir.path(), "psk", "secret\n");
+    let (pushed, pulled, written) = transfer(dir.path(), Some(psk.clone()), Some(psk)).await;
+    pushed.unwrap();
+    pulled.unwrap();
+    assert!(contains(&written, CONTENT));
+    assert_eq!(std::fs::read(dir.path().join("output")).unwrap(), CONTENT);
+}
+
+#[tokio::test]
+async fn mismatched_keys_send_no_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let secret = psk_file(dir.path(), "secret", "secret");
+    let guess = psk_file(dir.path(), "guess", "guess");
+    let cases = [
+        (Some(secret.clone()), Some(guess.clone())),
+        (Some(guess), Some(secret.clone())),
+        (Some(secret.clone()), None),
+        (None, Some(secret)),
+    ];
+    for (push_psk, pull_psk) in cases {
+        let (pushed, pulled, written) = transfer(dir.path(), push_psk, pull_psk).await;
+        assert_rejected(dir.path(), pushed);
+        assert_rejected(dir.path(), pulled);
+        assert!(!contains(&written, CONTENT));
+    }
+}
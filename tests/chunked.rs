@@ -0,0 +1,75 @@
+use std::{
+    io,
+    io::Write,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use file_transfer::{FileTransferCommand, PullFileArgs, PushFileArgs};
+use tokio::io::{AsyncRead, ReadBuf};
+
+const LEN: usize = 32 * 1024 * 1024;
+const GROWTH: &[u8] = b"appended once the pusher is sending";
+
+/// Appends [`GROWTH`] to `path` once `remaining` bytes have been read through it
+struct GrowAfter<R> {
+    inner: R,
+    remaining: usize,
+    path: Option<PathBuf>,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for GrowAfter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let read = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.remaining = self.remaining.saturating_sub(buf.filled().len() - filled);
+        if self.remaining == 0 {
+            if let Some(path) = self.path.take() {
+                let mut file = std::fs::File::options().append(true).open(path)?;
+                file.write_all(GROWTH)?;
+            }
+        }
+        read
+    }
+}
+
+#[tokio::test]
+async fn growth_while_sending_is_included() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let mut content: Vec<u8> = (0..LEN).map(|i| (i % 239) as u8).collect();
+    std::fs::write(&source, &content).unwrap();
+
+    let push = FileTransferCommand::Push(PushFileArgs {
+        chunked: true,
+        ..PushFileArgs::new(&source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        chunked: true,
+        ..PullFileArgs::new(&output)
+    });
+    let (push_stream, pull_stream) = tokio::io::duplex(1024 * 64);
+    let pushing = tokio::spawn(async move {
+        let (read, write) = tokio::io::split(push_stream);
+        push.perform(read, write).await.map(|result| result.stats)
+    });
+    let (read, write) = tokio::io::split(pull_stream);
+    // The pusher has measured the file long before the first MiB arrives
+    let read = GrowAfter {
+        inner: read,
+        remaining: 1024 * 1024,
+        path: Some(source.clone()),
+    };
+    let pulled = pull.perform(read, write).await.unwrap().stats;
+    let pushed = pushing.await.unwrap().unwrap();
+
+    content.extend_from_slice(GROWTH);
+    assert_eq!(pushed.bytes, content.len());
+    assert_eq!(pulled.bytes, content.len());
+    assert!(std::fs::read(&output).unwrap() == content);
+}
@@ -0,0 +1,19 @@
+use file_transfer::{FileTransferCommand, FileTransferError, FileTransferStats};
+
+/// Run `push` against `pull` over an in-memory stream and return the outcome of each side
+pub async fn transfer(
+    push: FileTransferCommand,
+    pull: FileTransferCommand,
+) -> (
+    Result<FileTransferStats, FileTransferError>,
+    Result<FileTransferStats, FileTransferError>,
+) {
+    let (push_stream, pull_stream) = tokio::io::duplex(1024 * 64);
+    let pushing = tokio::spawn(async move {
+        let (read, write) = tokio::io::split(push_stream);
+        push.perform(read, write).await.map(|result| result.stats)
+    });
+    let (read, write) = tokio::io::split(pull_stream);
+    let pulled = pull.perform(read, write).await.map(|result| result.stats);
+    (pushing.await.unwrap(), pulled)
+}
@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use file_transfer::{FileTransferCommand, FileTransferError, PullFileArgs, PushFileArgs};
+
+/// Push `source` into `output` over an in-memory stream, slowed down by `rate_limit`
+async fn transfer(
+    source: &Path,
+    output: &Path,
+    rate_limit: Option<u64>,
+) -> Result<(), FileTransferError> {
+    let (push_stream, pull_stream) = tokio::io::duplex(1024 * 64);
+    let push = FileTransferCommand::Push(PushFileArgs {
+        rate_limit,
+        rate_limit_burst: rate_limit.map(|_| 1024 * 64),
+        ..PushFileArgs::new(source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(output));
+    let pushing = tokio::spawn(async move {
+        let (read, write) = tokio::io::split(push_stream);
+        push.perform(read, write).await.map(|_| ())
+    });
+    let (read, write) = tokio::io::split(pull_stream);
+    let pulled = pull.perform(read, write).await.map(|_| ());
+    let pushed = pushing.await.unwrap();
+    pulled.and(pushed)
+}
+
+#[tokio::test]
+async fn concurrent_pulls_into_one_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let slow = dir.path().join("slow");
+    let fast = dir.path().join("fast");
+    let output = dir.path().join("output");
+    std::fs::write(&slow, vec![1; 1024 * 1024]).unwrap();
+    std::fs::write(&fast, vec![2; 1024 * 1024]).unwrap();
+
+    let first = tokio::spawn({
+        let (slow, output) = (slow.clone(), output.clone());
+        async move { transfer(&slow, &output, Some(1024 * 1024)).await }
+    });
+    // Let the first pull take the part file before the second one starts
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let second = transfer(&fast, &output, None).await;
+
+    let e = second.unwrap_err();
+    assert!(
+        e.to_string().contains("another transfer is writing"),
+        "unexpected error: {e}"
+    );
+    first.await.unwrap().unwrap();
+    assert_eq!(
+        std::fs::read(&output).unwrap(),
+        std::fs::read(&slow).unwrap()
+    );
+
+    // Once the first pull is done, the output can be pulled into again
+    transfer(&fast, &output, None).await.unwrap();
+    assert_eq!(
+        std::fs::read(&output).unwrap(),
+        std::fs::read(&fast).unwrap()
+    );
+}
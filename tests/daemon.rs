@@ -0,0 +1,182 @@
+use std::{io, net::SocketAddr, path::Path, time::Duration};
+
+use file_transfer::{
+    connect, daemon, ClientTlsArgs, DaemonArgs, DaemonEvent, FileTransferCommand,
+    FileTransferError, PullFileArgs, PushFileArgs, ServerTlsArgs,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+struct Daemon {
+    addr: SocketAddr,
+    events: mpsc::UnboundedReceiver<DaemonEvent>,
+    shutdown: oneshot::Sender<()>,
+    running: JoinHandle<Result<(), FileTransferError>>,
+}
+impl Daemon {
+    /// Serve `command` on a free local port, dropping peers that stall for `timeout` seconds
+    async fn start(command: FileTransferCommand, timeout: u64) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (report, events) = mpsc::unbounded_channel();
+        let (shutdown, stop) = oneshot::channel();
+        let args = DaemonArgs {
+            max_connections: 4,
+            timeout,
+        };
+        let running = tokio::spawn(async move {
+            let shutdown = async {
+                let _ = stop.await;
+            };
+            let report = move |event| {
+                let _ = report.send(event);
+            };
+            let tls = ServerTlsArgs::default();
+            daemon(listener, &args, &tls, &command, shutdown, report).await
+        });
+        Self {
+            addr,
+            events,
+            shutdown,
+            running,
+        }
+    }
+
+    /// Wait for the daemon to report on `peer`
+    async fn served(&mut self, peer: SocketAddr) -> Result<(), FileTransferError> {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), self.events.recv())
+                .await
+                .expect("the daemon reports on every peer")
+                .unwrap();
+            match event {
+                DaemonEvent::Served {
+                    peer: served,
+                    result,
+                } if served == peer => return result.map(|_| ()),
+                DaemonEvent::Served { .. } => {}
+                DaemonEvent::AcceptFailed(e) => panic!("failed to accept: {e}"),
+            }
+        }
+    }
+
+    async fn stop(self) {
+        self.shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), self.running)
+            .await
+            .expect("the daemon stops once its peers are done")
+            .unwrap()
+            .unwrap();
+    }
+}
+
+fn push_daemon(root: &Path) -> FileTransferCommand {
+    FileTransferCommand::Push(PushFileArgs::new(root))
+}
+
+#[tokio::test]
+async fn stalled_peer_times_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut daemon = Daemon::start(push_daemon(dir.path()), 1).await;
+
+    // Connect without ever sending anything
+    let stalled = TcpStream::connect(daemon.addr).await.unwrap();
+    let e = daemon
+        .served(stalled.local_addr().unwrap())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&e, FileTransferError::Io(e) if e.kind() == io::ErrorKind::TimedOut),
+        "unexpected error: {e}"
+    );
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn peers_are_served_concurrently() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("file"), b"served while another peer waits").unwrap();
+    let mut daemon = Daemon::start(push_daemon(&root), 60).await;
+
+    // Keep one peer busy for the whole transfer of the other
+    let idle = TcpStream::connect(daemon.addr).await.unwrap();
+    let output = dir.path().join("output");
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+    let port = daemon.addr.port();
+    let tls = ClientTlsArgs::default();
+    let pulling = connect("127.0.0.1", port, &tls, Some(Path::new("file")), &pull);
+    tokio::time::timeout(Duration::from_secs(10), pulling)
+        .await
+        .expect("the second peer is not held up by the first")
+        .unwrap();
+    assert_eq!(
+        std::fs::read(&output).unwrap(),
+        b"served while another peer waits"
+    );
+
+    let idle_addr = idle.local_addr().unwrap();
+    drop(idle);
+    assert!(daemon.served(idle_addr).await.is_err());
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn peer_asks_for_a_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir_all(root.join("tree/nested")).unwrap();
+    std::fs::write(root.join("tree/nested/file"), b"in a directory").unwrap();
+    // The daemon is not started recursive; the peer asks for it
+    let daemon = Daemon::start(push_daemon(&root), 60).await;
+    let port = daemon.addr.port();
+    let tls = ClientTlsArgs::default();
+
+    let output = dir.path().join("output");
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        recursive: true,
+        ..PullFileArgs::new(&output)
+    });
+    connect("127.0.0.1", port, &tls, Some(Path::new("tree")), &pull)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read(output.join("nested/file")).unwrap(),
+        b"in a directory"
+    );
+
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(dir.path().join("file")));
+    let e = connect("127.0.0.1", port, &tls, Some(Path::new("tree")), &pull)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&e, FileTransferError::Rejected { message, .. } if message.contains("not a file")),
+        "unexpected error: {e}"
+    );
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn peer_without_a_remote_path_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut daemon = Daemon::start(push_daemon(dir.path()), 60).await;
+
+    let stream = TcpStream::connect(daemon.addr).await.unwrap();
+    let peer = stream.local_addr().unwrap();
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(dir.path().join("output")));
+    let e = pull.perform_tcp(stream).await.unwrap_err();
+    assert!(
+        matches!(&e, FileTransferError::Incompatible(message) if message.contains("needs a remote path")),
+        "unexpected error: {e}"
+    );
+    let e = daemon.served(peer).await.unwrap_err();
+    assert!(matches!(e, FileTransferError::Incompatible(_)), "{e}");
+
+    daemon.stop().await;
+}
@@ -0,0 +1,24 @@
+use file_transfer::{pull_file, push_file, FileTransferError};
+
+#[tokio::test]
+async fn corrupted_content_is_discarded() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let content: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    std::fs::write(&source, &content).unwrap();
+
+    let (_, mut stream) = push_file(&source, vec![]).await.unwrap();
+    // Past the length and the codec, in the middle of the content
+    let corrupted = 8 + 1 + 50_000;
+    assert_eq!(stream[corrupted], content[50_000]);
+    stream[corrupted] ^= 0xff;
+
+    let e = pull_file(&output, std::io::Cursor::new(stream))
+        .await
+        .map(|(bytes, _)| bytes)
+        .unwrap_err();
+    assert!(matches!(e, FileTransferError::DigestMismatch(_)), "{e}");
+    assert!(!dir.path().join(".output.part").exists());
+    assert!(!output.exists());
+}
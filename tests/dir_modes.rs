@@ -0,0 +1,52 @@
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path};
+
+use file_transfer::{push_dir, PreserveArgs, PullFileArgs};
+
+async fn pull_dir_with(source: &Path, output: &Path, preserve: PreserveArgs) -> u32 {
+    let (mut push_stream, pull_stream) = tokio::io::duplex(1024 * 64);
+    let pushing = tokio::spawn({
+        let source = source.to_owned();
+        async move { push_dir(source, &mut push_stream).await.map(|_| ()) }
+    });
+    PullFileArgs {
+        recursive: true,
+        preserve,
+        ..PullFileArgs::new(output)
+    }
+    .pull_dir(pull_stream)
+    .await
+    .unwrap();
+    pushing.await.unwrap().unwrap();
+    std::fs::metadata(output.join("tool"))
+        .unwrap()
+        .permissions()
+        .mode()
+        & 0o7777
+}
+
+#[tokio::test]
+async fn special_bits_need_perms_and_owner() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    std::fs::create_dir(&source).unwrap();
+    std::fs::write(source.join("tool"), b"#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(source.join("tool"), Permissions::from_mode(0o4755)).unwrap();
+
+    let mode = pull_dir_with(&source, &dir.path().join("plain"), PreserveArgs::default()).await;
+    assert_eq!(mode, 0o755);
+
+    let perms = PreserveArgs {
+        perms: true,
+        ..PreserveArgs::default()
+    };
+    let mode = pull_dir_with(&source, &dir.path().join("perms"), perms).await;
+    assert_eq!(mode, 0o755);
+
+    let perms_and_owner = PreserveArgs {
+        perms: true,
+        owner: true,
+        ..PreserveArgs::default()
+    };
+    let mode = pull_dir_with(&source, &dir.path().join("owner"), perms_and_owner).await;
+    assert_eq!(mode, 0o4755);
+}
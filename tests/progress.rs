@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use file_transfer::{FileTransferCommand, Progress, PullFileArgs, PushFileArgs};
+use tokio::sync::watch;
+
+mod common;
+
+#[tokio::test]
+async fn progress_bar_with_progress_channel() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    std::fs::write(&source, vec![7; 1024 * 1024]).unwrap();
+
+    let (push_progress, push_updates) = watch::channel(Progress::default());
+    let (pull_progress, pull_updates) = watch::channel(Progress::default());
+    let push = FileTransferCommand::Push(PushFileArgs {
+        progress_bar: true,
+        progress: Some(push_progress),
+        ..PushFileArgs::new(&source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        progress_bar: true,
+        progress: Some(pull_progress),
+        ..PullFileArgs::new(&output)
+    });
+
+    // The callers keep their senders alive in the commands throughout
+    let (pushed, pulled) =
+        tokio::time::timeout(Duration::from_secs(10), common::transfer(push, pull))
+            .await
+            .expect("the transfer outlives its progress bars");
+    pushed.unwrap();
+    pulled.unwrap();
+
+    for updates in [push_updates, pull_updates] {
+        let progress = *updates.borrow();
+        assert_eq!(progress.total, 1024 * 1024);
+        assert_eq!(progress.bytes, 1024 * 1024);
+    }
+}
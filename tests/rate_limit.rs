@@ -0,0 +1,32 @@
+use file_transfer::{FileTransferCommand, PullFileArgs, PushFileArgs};
+
+mod common;
+
+#[tokio::test]
+async fn stats_report_the_measured_rate() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    std::fs::write(&source, vec![3; 512 * 1024]).unwrap();
+
+    let rate_limit = 1024 * 1024;
+    let push = FileTransferCommand::Push(PushFileArgs {
+        rate_limit: Some(rate_limit),
+        rate_limit_burst: Some(1024 * 64),
+        ..PushFileArgs::new(&source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+    let (pushed, pulled) = common::transfer(push, pull).await;
+    let stats = pushed.unwrap();
+    pulled.unwrap();
+
+    assert_eq!(stats.rate_limit, Some(rate_limit));
+    let measured = stats.wire_bytes as f64 / (stats.latency_ms / 1000.) / 1024. / 1024.;
+    assert!((stats.wire_throughput_mib_s - measured).abs() < 0.01);
+    // Only the burst goes out faster than 1 MiB/s
+    assert!(
+        stats.wire_throughput_mib_s < 1.2,
+        "throttled to 1 MiB/s but measured {stats}"
+    );
+    assert!(stats.to_string().contains("wire throughput:"));
+}
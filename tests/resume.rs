@@ -0,0 +1,162 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use file_transfer::{FileTransferCommand, FileTransferError, PullFileArgs, PushFileArgs};
+use tokio::io::{AsyncRead, ReadBuf};
+
+mod common;
+
+fn sample(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn part_path(output: &Path) -> PathBuf {
+    let name = output.file_name().unwrap().to_str().unwrap();
+    output.with_file_name(format!(".{name}.part"))
+}
+
+async fn resume(source: &Path, output: &Path) -> (usize, usize) {
+    let push = FileTransferCommand::Push(PushFileArgs {
+        resume: true,
+        ..PushFileArgs::new(source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        resume: true,
+        ..PullFileArgs::new(output)
+    });
+    let (pushed, pulled) = common::transfer(push, pull).await;
+    (pushed.unwrap().bytes, pulled.unwrap().bytes)
+}
+
+#[tokio::test]
+async fn partial_part_file_is_completed() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let content = sample(1024 * 1024, 0);
+    std::fs::write(&source, &content).unwrap();
+    // What an interrupted pull left behind
+    std::fs::write(part_path(&output), &content[..300_000]).unwrap();
+
+    let (pushed, pulled) = resume(&source, &output).await;
+    assert_eq!(pushed, content.len() - 300_000);
+    assert_eq!(pulled, content.len() - 300_000);
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+    assert!(!part_path(&output).exists());
+}
+
+#[tokio::test]
+async fn prefix_mismatch_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let content = sample(1024 * 1024, 0);
+    std::fs::write(&source, &content).unwrap();
+    std::fs::write(part_path(&output), sample(300_000, 0xff)).unwrap();
+
+    let (pushed, pulled) = resume(&source, &output).await;
+    assert_eq!(pushed, content.len());
+    assert_eq!(pulled, content.len());
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+}
+
+/// Applies `change` to the source file before handing out `request`
+///
+/// The pusher measures the source file before it reads the resume request,
+/// so this makes the file change between the two.
+struct ChangeThenRead<F> {
+    change: Option<F>,
+    request: io::Cursor<Vec<u8>>,
+}
+impl<F> ChangeThenRead<F> {
+    fn new(change: F, offset: u64, prefix: &[u8]) -> Self {
+        let mut request = offset.to_be_bytes().to_vec();
+        request.extend_from_slice(blake3::hash(prefix).as_bytes());
+        Self {
+            change: Some(change),
+            request: io::Cursor::new(request),
+        }
+    }
+}
+impl<F> AsyncRead for ChangeThenRead<F>
+where
+    F: FnOnce() + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(change) = self.change.take() {
+            change();
+        }
+        Pin::new(&mut self.request).poll_read(cx, buf)
+    }
+}
+
+#[tokio::test]
+async fn source_growing_while_the_prefix_is_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let content = sample(150_000, 0);
+    std::fs::write(&source, &content[..100_000]).unwrap();
+
+    // The puller claims a prefix longer than the file was when measured
+    let grow = {
+        let (source, content) = (source.clone(), content.clone());
+        move || std::fs::write(&source, &content).unwrap()
+    };
+    let read = ChangeThenRead::new(grow, 120_000, &content[..120_000]);
+    let e = file_transfer::push_file_resume(&source, read, tokio::io::sink())
+        .await
+        .map(|(bytes, _, _)| bytes)
+        .unwrap_err();
+    assert!(
+        matches!(
+            e,
+            FileTransferError::FileChanged {
+                expected: 100_000,
+                actual: 120_000
+            }
+        ),
+        "{e}"
+    );
+}
+
+#[tokio::test]
+async fn source_shrinking_while_the_prefix_is_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let content = sample(100_000, 0);
+    std::fs::write(&source, &content).unwrap();
+
+    let shrink = {
+        let source = source.clone();
+        move || {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&source)
+                .unwrap();
+            file.set_len(30_000).unwrap();
+        }
+    };
+    let read = ChangeThenRead::new(shrink, 50_000, &content[..50_000]);
+    let e = file_transfer::push_file_resume(&source, read, tokio::io::sink())
+        .await
+        .map(|(bytes, _, _)| bytes)
+        .unwrap_err();
+    assert!(
+        matches!(
+            e,
+            FileTransferError::FileChanged {
+                expected: 100_000,
+                actual: 30_000
+            }
+        ),
+        "{e}"
+    );
+}
@@ -0,0 +1,101 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use file_transfer::{
+    FileTransferCommand, FileTransferStats, PreserveArgs, PullFileArgs, PushFileArgs,
+};
+
+mod common;
+
+/// Push `source` to `output` asking to skip it if unchanged, preserving times if `times`
+async fn sync(source: &Path, output: &Path, times: bool) -> (FileTransferStats, FileTransferStats) {
+    let push = FileTransferCommand::Push(PushFileArgs {
+        skip_unchanged: true,
+        ..PushFileArgs::new(source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        skip_unchanged: true,
+        preserve: PreserveArgs {
+            times,
+            ..PreserveArgs::default()
+        },
+        ..PullFileArgs::new(output)
+    });
+    let (pushed, pulled) = common::transfer(push, pull).await;
+    (pushed.unwrap(), pulled.unwrap())
+}
+
+#[tokio::test]
+async fn identical_output_is_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    std::fs::write(&source, b"already there").unwrap();
+    std::fs::write(&output, b"already there").unwrap();
+
+    let (pushed, pulled) = sync(&source, &output, false).await;
+    for stats in [pushed, pulled] {
+        assert!(stats.skipped);
+        assert_eq!(stats.bytes, 0);
+        assert!(stats.to_string().contains("skipped;"), "{stats}");
+    }
+    assert_eq!(std::fs::read(&output).unwrap(), b"already there");
+}
+
+#[tokio::test]
+async fn output_of_another_size_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    std::fs::write(&source, b"the new content").unwrap();
+    std::fs::write(&output, b"old").unwrap();
+
+    let (pushed, pulled) = sync(&source, &output, false).await;
+    for stats in [pushed, pulled] {
+        assert!(!stats.skipped);
+        assert_eq!(stats.bytes, b"the new content".len());
+        assert!(!stats.to_string().contains("skipped"), "{stats}");
+    }
+    assert_eq!(std::fs::read(&output).unwrap(), b"the new content");
+}
+
+#[tokio::test]
+async fn output_of_another_mtime_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    std::fs::write(&source, b"same content").unwrap();
+
+    let (_, pulled) = sync(&source, &output, true).await;
+    assert!(!pulled.skipped);
+    let (_, pulled) = sync(&source, &output, true).await;
+    assert!(pulled.skipped);
+
+    // Only the modification time of the source changes
+    let touched = SystemTime::now() + Duration::from_secs(60);
+    std::fs::File::options()
+        .write(true)
+        .open(&source)
+        .unwrap()
+        .set_modified(touched)
+        .unwrap();
+    let (pushed, pulled) = sync(&source, &output, true).await;
+    assert!(!pushed.skipped);
+    assert!(!pulled.skipped);
+    assert_eq!(
+        std::fs::metadata(&output).unwrap().modified().unwrap(),
+        touched
+    );
+
+    // Without preserving times, the same content is enough
+    std::fs::File::options()
+        .write(true)
+        .open(&output)
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let (_, pulled) = sync(&source, &output, false).await;
+    assert!(pulled.skipped);
+}
@@ -0,0 +1,54 @@
+use std::{
+    io::{Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use file_transfer::{FileTransferCommand, PullFileArgs, PushFileArgs};
+
+mod common;
+
+const LEN: u64 = 16 * 1024 * 1024;
+
+/// Bytes actually allocated to the file at `path`
+fn allocated(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().blocks() * 512
+}
+
+#[tokio::test]
+async fn holes_are_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let mut file = std::fs::File::create(&source).unwrap();
+    file.write_all(&[1; 64 * 1024]).unwrap();
+    file.seek(SeekFrom::Start(LEN / 2)).unwrap();
+    file.write_all(&[2; 64 * 1024]).unwrap();
+    // The file ends in a hole
+    file.set_len(LEN).unwrap();
+    drop(file);
+    if allocated(&source) >= LEN / 4 {
+        eprintln!("skipped: the file system does not keep holes");
+        return;
+    }
+
+    let push = FileTransferCommand::Push(PushFileArgs {
+        sparse: true,
+        ..PushFileArgs::new(&source)
+    });
+    let pull = FileTransferCommand::Pull(PullFileArgs {
+        sparse: true,
+        ..PullFileArgs::new(&output)
+    });
+    let (pushed, pulled) = common::transfer(push, pull).await;
+    pushed.unwrap();
+    pulled.unwrap();
+
+    assert!(std::fs::read(&output).unwrap() == std::fs::read(&source).unwrap());
+    assert_eq!(std::fs::metadata(&output).unwrap().len(), LEN);
+    assert!(
+        allocated(&output) < LEN / 4,
+        "{} of {LEN} bytes allocated",
+        allocated(&output)
+    );
+}
@@ -0,0 +1,134 @@
+use std::io::Cursor;
+
+use file_transfer::{FileTransferCommand, FileTransferError, PullFileArgs, PushFileArgs};
+
+mod common;
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 % 257) as u8).collect()
+}
+
+#[tokio::test]
+async fn stream_of_unknown_length_into_a_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+    let content = sample(3 * 1024 * 1024 + 5);
+
+    let push = PushFileArgs::new("-");
+    let (pushed, wire) = push
+        .push_stream(Cursor::new(content.clone()), vec![])
+        .await
+        .unwrap();
+    let pull = PullFileArgs {
+        chunked: true,
+        ..PullFileArgs::new(&output)
+    };
+    let (pulled, _) = pull.pull_file(Cursor::new(wire)).await.unwrap();
+
+    assert_eq!(pushed, content.len());
+    assert_eq!(pulled, content.len());
+    assert!(std::fs::read(&output).unwrap() == content);
+}
+
+#[tokio::test]
+async fn file_into_a_writer() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let content = sample(1024 * 1024 + 1);
+    std::fs::write(&source, &content).unwrap();
+
+    for chunked in [false, true] {
+        let push = PushFileArgs {
+            chunked,
+            ..PushFileArgs::new(&source)
+        };
+        let (_, wire) = push.push_file(vec![]).await.unwrap();
+        let pull = PullFileArgs {
+            chunked,
+            ..PullFileArgs::new("-")
+        };
+        let mut sink = vec![];
+        let (pulled, _) = pull
+            .pull_stream(Cursor::new(wire), &mut sink)
+            .await
+            .unwrap();
+        assert_eq!(pulled, content.len());
+        assert!(sink == content);
+    }
+}
+
+#[tokio::test]
+async fn stream_into_a_writer() {
+    let content = sample(200_000);
+    let push = PushFileArgs::new("-");
+    let (_, wire) = push
+        .push_stream(Cursor::new(content.clone()), vec![])
+        .await
+        .unwrap();
+    let pull = PullFileArgs {
+        chunked: true,
+        ..PullFileArgs::new("-")
+    };
+    let mut sink = vec![];
+    pull.pull_stream(Cursor::new(wire), &mut sink)
+        .await
+        .unwrap();
+    assert!(sink == content);
+}
+
+fn assert_incompatible<T: std::fmt::Debug>(result: Result<T, FileTransferError>) {
+    match result {
+        Err(FileTransferError::Incompatible(_)) => {}
+        other => panic!("expected the peers to disagree, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn stdout_refuses_features_it_cannot_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    std::fs::write(&source, b"never written to stdout").unwrap();
+    let pushes = [
+        PushFileArgs {
+            resume: true,
+            ..PushFileArgs::new(&source)
+        },
+        PushFileArgs {
+            delta: true,
+            ..PushFileArgs::new(&source)
+        },
+        PushFileArgs {
+            skip_unchanged: true,
+            ..PushFileArgs::new(&source)
+        },
+    ];
+    for push in pushes {
+        let push = FileTransferCommand::Push(push);
+        let pull = FileTransferCommand::Pull(PullFileArgs::new("-"));
+        let (pushed, pulled) = common::transfer(push, pull).await;
+        assert_incompatible(pushed);
+        assert_incompatible(pulled);
+    }
+}
+
+#[tokio::test]
+async fn stdout_refuses_several_streams() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    std::fs::write(&source, b"never written to stdout").unwrap();
+    let (push_streams, pull_streams): (Vec<_>, Vec<_>) = (0..2)
+        .map(|_| {
+            let (push, pull) = tokio::io::duplex(1024 * 64);
+            (tokio::io::split(push), tokio::io::split(pull))
+        })
+        .unzip();
+
+    let push = FileTransferCommand::Push(PushFileArgs::new(&source));
+    let pull = FileTransferCommand::Pull(PullFileArgs::new("-"));
+    let (pushed, pulled) = tokio::join!(
+        push.perform_parallel(push_streams),
+        pull.perform_parallel(pull_streams),
+    );
+    assert_incompatible(pushed.map(|result| result.stats));
+    assert_incompatible(pulled.map(|result| result.stats));
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use file_transfer::{
+    ClientTlsArgs, FileTransferCommand, FileTransferError, PullFileArgs, PushFileArgs,
+    ServerTlsArgs,
+};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+
+const SERVER_NAME: &str = "localhost";
+
+/// A self-signed CA written to `<name>-ca.pem` in `dir`
+fn ca(dir: &Path, name: &str) -> (CertifiedIssuer<'static, KeyPair>, PathBuf) {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+    let path = dir.join(format!("{name}-ca.pem"));
+    std::fs::write(&path, ca.pem()).unwrap();
+    (ca, path)
+}
+
+/// A certificate signed by `ca` written with its key to `<name>.pem` and `<name>-key.pem` in `dir`
+fn leaf(
+    dir: &Path,
+    name: &str,
+    ca: &CertifiedIssuer<'static, KeyPair>,
+    usage: ExtendedKeyUsagePurpose,
+) -> (PathBuf, PathBuf) {
+    let mut params = CertificateParams::new(vec![SERVER_NAME.to_owned()]).unwrap();
+    params.extended_key_usages = vec![usage];
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, ca).unwrap();
+    let cert_path = dir.join(format!("{name}.pem"));
+    let key_path = dir.join(format!("{name}-key.pem"));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+struct Pki {
+    dir: tempfile::TempDir,
+    server: ServerTlsArgs,
+    /// Trusts the server but presents no certificate
+    client: ClientTlsArgs,
+    /// Client certificate signed by the CA the server trusts for clients
+    client_cert: (PathBuf, PathBuf),
+    /// Client certificate signed by a CA nobody trusts
+    stranger_cert: (PathBuf, PathBuf),
+}
+impl Pki {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let (server_ca, server_ca_path) = ca(dir.path(), "server");
+        let (client_ca, client_ca_path) = ca(dir.path(), "client");
+        let (stranger_ca, _) = ca(dir.path(), "stranger");
+        let (server_cert, server_key) = leaf(
+            dir.path(),
+            "server",
+            &server_ca,
+            ExtendedKeyUsagePurpose::ServerAuth,
+        );
+        let client_cert = leaf(
+            dir.path(),
+            "client",
+            &client_ca,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        );
+        let stranger_cert = leaf(
+            dir.path(),
+            "stranger",
+            &stranger_ca,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        );
+        Self {
+            server: ServerTlsArgs {
+                tls_cert: Some(server_cert),
+                tls_key: Some(server_key),
+                tls_client_ca: Some(client_ca_path),
+            },
+            client: ClientTlsArgs {
+                tls_ca: Some(server_ca_path),
+                ..ClientTlsArgs::default()
+            },
+            dir,
+            client_cert,
+            stranger_cert,
+        }
+    }
+
+    fn server_only(&self) -> ServerTlsArgs {
+        ServerTlsArgs {
+            tls_client_ca: None,
+            ..self.server.clone()
+        }
+    }
+
+    fn client_with(&self, (cert, key): &(PathBuf, PathBuf)) -> ClientTlsArgs {
+        ClientTlsArgs {
+            tls_cert: Some(cert.clone()),
+            tls_key: Some(key.clone()),
+            ..self.client.clone()
+        }
+    }
+}
+
+/// Push `source` from the TLS server to `output` on the TLS client over an in-memory stream
+///
+/// Return the outcomes of the server and the client.
+async fn transfer(
+    server: &ServerTlsArgs,
+    client: &ClientTlsArgs,
+    source: &Path,
+    output: &Path,
+) -> (Result<(), FileTransferError>, Result<(), FileTransferError>) {
+    let (server_stream, client_stream) = tokio::io::duplex(1024 * 64);
+    let acceptor = server.acceptor().unwrap().unwrap();
+    let connector = client.connector().unwrap().unwrap();
+    let push = FileTransferCommand::Push(PushFileArgs::new(source));
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(output));
+
+    let serving = async {
+        let stream = acceptor.accept(server_stream).await?;
+        let (read, write) = tokio::io::split(stream);
+        push.perform(read, write).await?;
+        Ok(())
+    };
+    let connecting = async {
+        let server_name = client.server_name(SERVER_NAME)?;
+        let stream = connector.connect(server_name, client_stream).await?;
+        let (read, write) = tokio::io::split(stream);
+        pull.perform(read, write).await?;
+        Ok(())
+    };
+    tokio::join!(serving, connecting)
+}
+
+fn source(pki: &Pki) -> PathBuf {
+    let path = pki.dir.path().join("source");
+    std::fs::write(&path, b"sent over TLS").unwrap();
+    path
+}
+
+#[tokio::test]
+async fn server_only_tls() {
+    let pki = Pki::new();
+    let source = source(&pki);
+    let output = pki.dir.path().join("output");
+
+    let (served, connected) = transfer(&pki.server_only(), &pki.client, &source, &output).await;
+    served.unwrap();
+    connected.unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"sent over TLS");
+}
+
+#[tokio::test]
+async fn mutual_tls() {
+    let pki = Pki::new();
+    let source = source(&pki);
+    let output = pki.dir.path().join("output");
+
+    let client = pki.client_with(&pki.client_cert);
+    let (served, connected) = transfer(&pki.server, &client, &source, &output).await;
+    served.unwrap();
+    connected.unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"sent over TLS");
+}
+
+#[tokio::test]
+async fn client_without_certificate_is_rejected() {
+    let pki = Pki::new();
+    let source = source(&pki);
+    let output = pki.dir.path().join("output");
+
+    let (served, connected) = transfer(&pki.server, &pki.client, &source, &output).await;
+    assert!(served.is_err());
+    assert!(connected.is_err());
+    assert!(!output.exists());
+}
+
+#[tokio::test]
+async fn client_with_untrusted_certificate_is_rejected() {
+    let pki = Pki::new();
+    let source = source(&pki);
+    let output = pki.dir.path().join("output");
+
+    let client = pki.client_with(&pki.stranger_cert);
+    let (served, connected) = transfer(&pki.server, &client, &source, &output).await;
+    assert!(served.is_err());
+    assert!(connected.is_err());
+    assert!(!output.exists());
+}
@@ -0,0 +1,29 @@
+use file_transfer::{FileTransferCommand, PullFileArgs, PushFileArgs};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Plain TCP lets the content move between file and socket inside the kernel on Linux
+#[tokio::test]
+async fn tcp_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let output = dir.path().join("output");
+    let content: Vec<u8> = (0..5 * 1024 * 1024 + 7).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&source, &content).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connecting, listener.accept());
+    let push = FileTransferCommand::Push(PushFileArgs::new(&source));
+    let pull = FileTransferCommand::Pull(PullFileArgs::new(&output));
+    let (pushed, pulled) = tokio::join!(
+        push.perform_tcp(connected.unwrap()),
+        pull.perform_tcp(accepted.unwrap().0),
+    );
+
+    // The puller has checked the digest sent after the content
+    let (pushed, pulled) = (pushed.unwrap().stats, pulled.unwrap().stats);
+    assert_eq!(pushed.bytes, content.len());
+    assert_eq!(pulled.bytes, content.len());
+    assert!(pulled.wire_bytes > content.len());
+    assert!(std::fs::read(&output).unwrap() == content);
+}